@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rustgb::disassembler::{Disassembler, ILLEGAL_OPCODES};
+
+// every legal opcode followed by two immediate bytes, prefixed ones included
+fn program() -> Vec<[u8; 3]> {
+    let base = (0..=0xFFu8)
+        .filter(|opcode| *opcode != 0xCB && !ILLEGAL_OPCODES.contains(opcode))
+        .map(|opcode| [opcode, 0x34, 0x12]);
+    let prefixed = (0..=0xFFu8).map(|opcode| [0xCB, opcode, 0x00]);
+    base.chain(prefixed).collect()
+}
+
+fn decode(c: &mut Criterion) {
+    let program = program();
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            for bytes in &program {
+                let mut bytes = bytes.iter().copied();
+                black_box(Disassembler::decode(|| bytes.next().unwrap()).unwrap());
+            }
+        })
+    });
+    group.bench_function("bits", |b| {
+        b.iter(|| {
+            for bytes in &program {
+                let mut bytes = bytes.iter().copied();
+                black_box(Disassembler::decode_bits(|| bytes.next().unwrap()).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
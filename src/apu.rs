@@ -1,8 +1,827 @@
+// audio processing unit
 
-struct Apu {}
+use crate::error::Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+use log::debug;
+use std::sync::{Arc, Mutex};
+
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const SCOPE_LEN: usize = 512;
+const CPU_CLOCK: u32 = 4_194_304;
+const FRAME_SEQUENCER_PERIOD: u32 = 2048; // in machine cycles, 512 Hz
+
+// OR-ed onto every read, unused and write-only bits read back as 1. Indexed by addr - 0xFF10.
+const READ_MASK: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// a channel timer from a save state, it never counts down from more than the longest period
+fn load_timer(state: &mut StateReader, max_period: i32) -> Result<i32, Error> {
+    let timer = state.i32()?;
+    if !(0..=max_period).contains(&timer) {
+        return Err(Error::InvalidSaveState(format!(
+            "APU timer {} is out of range",
+            timer
+        )));
+    }
+    Ok(timer)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AudioChannel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; 4] = [
+        AudioChannel::Square1,
+        AudioChannel::Square2,
+        AudioChannel::Wave,
+        AudioChannel::Noise,
+    ];
+
+    pub fn index(&self) -> usize {
+        match self {
+            AudioChannel::Square1 => 0,
+            AudioChannel::Square2 => 1,
+            AudioChannel::Wave => 2,
+            AudioChannel::Noise => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioChannel::Square1 => "Square 1",
+            AudioChannel::Square2 => "Square 2",
+            AudioChannel::Wave => "Wave",
+            AudioChannel::Noise => "Noise",
+        }
+    }
+}
+
+// last SCOPE_LEN samples of every channel's DAC output (-1.0..=1.0) and of the mixed output
+pub struct Oscilloscope {
+    pub channels: [Vec<f32>; 4],
+    pub mixed: Vec<f32>,
+}
+
+impl Default for Oscilloscope {
+    fn default() -> Self {
+        Self {
+            channels: core::array::from_fn(|_| vec![0.0; SCOPE_LEN]),
+            mixed: vec![0.0; SCOPE_LEN],
+        }
+    }
+}
+
+#[derive(Default)]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    // returns true if the channel should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.bool(self.enabled);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.counter = state.u16()?;
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0b1000 != 0;
+        self.period = value & 0b111;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.initial_volume = state.u8()?;
+        self.increase = state.bool()?;
+        self.period = state.u8()?;
+        self.volume = state.u8()?;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0b111;
+        self.negate = value & 0b1000 != 0;
+        self.shift = value & 0b111;
+    }
+
+    // returns None if the new frequency overflows
+    fn calculate(&self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift;
+        let frequency = if self.negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        (frequency <= 2047).then_some(frequency)
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.period = state.u8()?;
+        self.negate = state.bool()?;
+        self.shift = state.u8()?;
+        self.timer = state.u8()?;
+        self.enabled = state.bool()?;
+        self.shadow_frequency = state.u16()? & 0x7FF;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: i32,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Sweep, // only used by channel 1
+}
+
+impl SquareChannel {
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn tick(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) & 7;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn trigger_sweep(&mut self) {
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.calculate().is_none() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+        if !self.sweep.enabled || self.sweep.period == 0 {
+            return;
+        }
+        match self.sweep.calculate() {
+            Some(frequency) if self.sweep.shift != 0 => {
+                self.frequency = frequency;
+                self.sweep.shadow_frequency = frequency;
+                // the new frequency is checked for overflow once more, but not written back
+                if self.sweep.calculate().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => {}
+            None => self.enabled = false,
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.duty);
+        state.u8(self.duty_step);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+        self.sweep.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.duty = state.u8()? & 3;
+        self.duty_step = state.u8()? & 7;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = load_timer(state, 2048 * 4)?;
+        self.length.load(state)?;
+        self.envelope.load(state)?;
+        self.sweep.load(state)
+    }
+}
+
+#[derive(Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_shift: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    sample: u8,
+    length: LengthCounter,
+}
+
+impl WaveChannel {
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn tick(&mut self, t_cycles: i32, wave_ram: &[u8]) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 31;
+            let byte = wave_ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        self.sample >> self.volume_shift
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.volume_shift);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        state.u8(self.position);
+        state.u8(self.sample);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.volume_shift = state.u8()?.min(4);
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = load_timer(state, 2048 * 2)?;
+        self.position = state.u8()? & 31;
+        self.sample = state.u8()? & 0x0F;
+        self.length.load(state)
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    width_7: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: i32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            width_7: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl NoiseChannel {
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn tick(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_7 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.clock_shift);
+        state.bool(self.width_7);
+        state.u8(self.divisor_code);
+        state.u16(self.lfsr);
+        state.i32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.clock_shift = state.u8()? & 0x0F;
+        self.width_7 = state.bool()?;
+        self.divisor_code = state.u8()? & 0b111;
+        self.lfsr = state.u16()? & 0x7FFF;
+        self.timer = load_timer(state, NOISE_DIVISORS[7] << 15)?;
+        self.length.load(state)?;
+        self.envelope.load(state)
+    }
+}
+
+pub struct Apu {
+    registers: [u8; 0x17], // raw NR10-NR52 as last written
+    wave_ram: [u8; 0x10],
+    powered: bool,
+    square_1: SquareChannel,
+    square_2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    frame_sequencer_counter: u32,
+    frame_sequencer_step: u8,
+    sample_counter: u32,
+    muted: [bool; 4],
+    solo: [bool; 4],
+    scope: Oscilloscope,
+    scope_pos: usize,
+    pub oscilloscope: Arc<Mutex<Oscilloscope>>,
+    pub collect_samples: bool,
+    samples: Vec<(f32, f32)>, // mixed (left, right) output since the last take_samples
+}
 
 impl Apu {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(oscilloscope: Arc<Mutex<Oscilloscope>>) -> Self {
+        Self {
+            registers: [0; 0x17],
+            wave_ram: [0; 0x10],
+            powered: true,
+            square_1: SquareChannel::default(),
+            square_2: SquareChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            frame_sequencer_counter: 0,
+            frame_sequencer_step: 0,
+            sample_counter: 0,
+            muted: [false; 4],
+            solo: [false; 4],
+            scope: Oscilloscope::default(),
+            scope_pos: 0,
+            oscilloscope,
+            collect_samples: false,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: AudioChannel, muted: bool) {
+        debug!("{} muted: {muted}", channel.name());
+        self.muted[channel.index()] = muted;
+    }
+
+    pub fn set_solo(&mut self, channel: AudioChannel, solo: bool) {
+        debug!("{} solo: {solo}", channel.name());
+        self.solo[channel.index()] = solo;
+    }
+
+    // a channel is heard if it isn't muted and either nothing or the channel itself is soloed
+    pub fn is_audible(&self, channel: AudioChannel) -> bool {
+        let index = channel.index();
+        !self.muted[index] && (self.solo[index] || !self.solo.iter().any(|&s| s))
+    }
+
+    // one machine cycle
+    pub fn cycle(&mut self) {
+        if self.powered {
+            self.square_1.tick(4);
+            self.square_2.tick(4);
+            self.wave.tick(4, &self.wave_ram);
+            self.noise.tick(4);
+
+            self.frame_sequencer_counter += 1;
+            if self.frame_sequencer_counter >= FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_counter = 0;
+                self.clock_frame_sequencer();
+            }
+        }
+
+        self.sample_counter += SAMPLE_RATE * 4;
+        if self.sample_counter >= CPU_CLOCK {
+            self.sample_counter -= CPU_CLOCK;
+            self.sample();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_sequencer_step & 1 == 0 {
+            if self.square_1.length.clock() {
+                self.square_1.enabled = false;
+            }
+            if self.square_2.length.clock() {
+                self.square_2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.square_1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.square_1.envelope.clock();
+            self.square_2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    // converts the digital channel outputs (0-15) to analog (-1.0..=1.0)
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| {
+            if enabled {
+                digital as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.square_1.dac_enabled, self.square_1.output()),
+            dac(self.square_2.dac_enabled, self.square_2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.dac_enabled, self.noise.output()),
+        ]
+    }
+
+    // returns the (left, right) output of the mixer, honouring mute and solo
+    fn mix(&self, outputs: &[f32; 4]) -> (f32, f32) {
+        let panning = self.registers[0x15]; // NR51
+        let volume = self.registers[0x14]; // NR50
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for channel in AudioChannel::ALL {
+            if !self.is_audible(channel) {
+                continue;
+            }
+            let index = channel.index();
+            if panning & (1 << (index + 4)) != 0 {
+                left += outputs[index];
+            }
+            if panning & (1 << index) != 0 {
+                right += outputs[index];
+            }
+        }
+        let left_volume = ((volume >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (volume & 0b111) as f32 + 1.0;
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    // the samples collected since the last call, at SAMPLE_RATE
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn sample(&mut self) {
+        let outputs = self.dac_outputs();
+        let (left, right) = self.mix(&outputs);
+        if self.collect_samples {
+            self.samples.push((left, right));
+        }
+        for (scope, output) in self.scope.channels.iter_mut().zip(outputs) {
+            scope[self.scope_pos] = output;
+        }
+        self.scope.mixed[self.scope_pos] = (left + right) / 2.0;
+        self.scope_pos += 1;
+        if self.scope_pos == SCOPE_LEN {
+            self.scope_pos = 0;
+            let mut oscilloscope = self.oscilloscope.lock().unwrap();
+            for (shared, scope) in oscilloscope.channels.iter_mut().zip(&self.scope.channels) {
+                shared.copy_from_slice(scope);
+            }
+            oscilloscope.mixed.copy_from_slice(&self.scope.mixed);
+        }
+    }
+
+    fn power_off(&mut self) {
+        debug!("APU powered off");
+        self.powered = false;
+        self.registers = [0; 0x17];
+        self.square_1 = SquareChannel::default();
+        self.square_2 = SquareChannel::default();
+        self.wave = WaveChannel::default();
+        self.noise = NoiseChannel::default();
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                let mut reg = 0x70;
+                reg |= (self.powered as u8) << 7;
+                reg |= (self.noise.enabled as u8) << 3;
+                reg |= (self.wave.enabled as u8) << 2;
+                reg |= (self.square_2.enabled as u8) << 1;
+                reg |= self.square_1.enabled as u8;
+                reg
+            }
+            0xFF10..=0xFF25 => {
+                let index = (addr - 0xFF10) as usize;
+                self.registers[index] | READ_MASK[index]
+            }
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize],
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => {
+                let powered = value & 0x80 != 0;
+                if self.powered && !powered {
+                    self.power_off();
+                } else if !self.powered && powered {
+                    debug!("APU powered on");
+                    self.powered = true;
+                    self.frame_sequencer_step = 0;
+                }
+                return;
+            }
+            0xFF30..=0xFF3F => {
+                self.wave_ram[(addr - 0xFF30) as usize] = value;
+                return;
+            }
+            0xFF27..=0xFF2F => return,
+            _ if !self.powered => return, // registers are read-only while powered off
+            _ => {}
+        }
+
+        self.registers[(addr - 0xFF10) as usize] = value;
+        match addr {
+            0xFF10 => self.square_1.sweep.write(value),
+            0xFF11 | 0xFF16 => {
+                let channel = self.square(addr);
+                channel.duty = value >> 6;
+                channel.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            0xFF12 | 0xFF17 => {
+                let channel = self.square(addr);
+                channel.envelope.write(value);
+                channel.dac_enabled = value & 0xF8 != 0;
+                if !channel.dac_enabled {
+                    channel.enabled = false;
+                }
+            }
+            0xFF13 | 0xFF18 => {
+                let channel = self.square(addr);
+                channel.frequency = (channel.frequency & 0x700) | value as u16;
+            }
+            0xFF14 | 0xFF19 => {
+                let channel = self.square(addr);
+                channel.frequency = (channel.frequency & 0xFF) | ((value as u16 & 0b111) << 8);
+                channel.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    channel.trigger();
+                    if addr == 0xFF14 {
+                        channel.trigger_sweep();
+                    }
+                }
+            }
+            0xFF1A => {
+                self.wave.dac_enabled = value & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            }
+            0xFF1B => self.wave.length.counter = 256 - value as u16,
+            0xFF1C => {
+                self.wave.volume_shift = match (value >> 5) & 0b11 {
+                    0b00 => 4,
+                    0b01 => 0,
+                    0b10 => 1,
+                    0b11 => 2,
+                    _ => unreachable!(),
+                };
+            }
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.wave.frequency =
+                    (self.wave.frequency & 0xFF) | ((value as u16 & 0b111) << 8);
+                self.wave.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            0xFF20 => self.noise.length.counter = 64 - (value & 0x3F) as u16,
+            0xFF21 => {
+                self.noise.envelope.write(value);
+                self.noise.dac_enabled = value & 0xF8 != 0;
+                if !self.noise.dac_enabled {
+                    self.noise.enabled = false;
+                }
+            }
+            0xFF22 => {
+                self.noise.clock_shift = value >> 4;
+                self.noise.width_7 = value & 0b1000 != 0;
+                self.noise.divisor_code = value & 0b111;
+            }
+            0xFF23 => {
+                self.noise.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            _ => {} // NR50, NR51 and the unused registers are only stored
+        }
+    }
+
+    fn square(&mut self, addr: u16) -> &mut SquareChannel {
+        if addr < 0xFF15 {
+            &mut self.square_1
+        } else {
+            &mut self.square_2
+        }
+    }
+}
+
+// muting, soloing and the oscilloscope belong to the frontend and aren't saved
+impl Snapshot for Apu {
+    fn save(&self, state: &mut StateWriter) {
+        state.section(b"APU ");
+        state.bytes(&self.registers);
+        state.bytes(&self.wave_ram);
+        state.bool(self.powered);
+        self.square_1.save(state);
+        self.square_2.save(state);
+        self.wave.save(state);
+        self.noise.save(state);
+        state.u32(self.frame_sequencer_counter);
+        state.u8(self.frame_sequencer_step);
+        state.u32(self.sample_counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.section(b"APU ")?;
+        state.bytes_into(&mut self.registers)?;
+        state.bytes_into(&mut self.wave_ram)?;
+        self.powered = state.bool()?;
+        self.square_1.load(state)?;
+        self.square_2.load(state)?;
+        self.wave.load(state)?;
+        self.noise.load(state)?;
+        self.frame_sequencer_counter = state.u32()?;
+        self.frame_sequencer_step = state.u8()? & 7;
+        self.sample_counter = state.u32()?;
+        Ok(())
     }
 }
@@ -0,0 +1,174 @@
+use crate::disassembler::Disassembler;
+use crate::isa::{Instruction, MiscInstruction};
+use crate::memory::Memory;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+// longest run of instructions decoded into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct CachedInstruction {
+    pub addr: u16,
+    pub len: u16, // in bytes, including the opcode
+    pub instruction: Instruction,
+}
+
+// straight-line code up to and including the next jump, call or return
+struct Block {
+    instructions: Vec<CachedInstruction>,
+    pages: (usize, usize), // first and last RAM page the block was decoded from
+}
+
+// decoded basic blocks keyed by start address and ROM bank. Blocks are only built from ROM,
+// WRAM and HRAM, writes into RAM pages holding cached code drop the blocks in them, and so does
+// switching the WRAM bank under them
+pub struct BlockCache {
+    blocks: HashMap<(u16, usize), Arc<Block>>,
+    code_pages: [bool; 256],
+    current: Option<(Arc<Block>, usize)>, // block being executed and index of the next instruction
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ROM banks, WRAM with its echo and HRAM. The rest is either IO or can change without the
+// CPU writing to it
+fn region(addr: u16) -> Option<u8> {
+    match addr {
+        0x0000..=0x3FFF => Some(0),
+        0x4000..=0x7FFF => Some(1),
+        0xC000..=0xFDFF => Some(2),
+        0xFF80..=0xFFFE => Some(3),
+        _ => None,
+    }
+}
+
+// 256 byte page of a RAM address, with echo RAM mapped onto WRAM
+fn page(addr: u16) -> usize {
+    match addr {
+        0xE000..=0xFDFF => (addr - 0x2000) as usize >> 8,
+        _ => addr as usize >> 8,
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump(_)
+            | Instruction::Misc(MiscInstruction::Halt)
+            | Instruction::Misc(MiscInstruction::Stop)
+            | Instruction::Misc(MiscInstruction::Illegal(_))
+    )
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            code_pages: [false; 256],
+            current: None,
+        }
+    }
+
+    // the instruction at pc, decoding a new block if it isn't cached. Returns None for code that
+    // can't be cached, which has to be run by the interpreter
+    pub fn next<M: Memory>(&mut self, mem: &M, pc: u16) -> Option<CachedInstruction> {
+        if let Some((block, index)) = &mut self.current {
+            if let Some(cached) = block.instructions.get(*index) {
+                if cached.addr == pc {
+                    *index += 1;
+                    return Some(*cached);
+                }
+            }
+        }
+
+        region(pc)?;
+        let key = (pc, mem.rom_bank(pc));
+        let block = match self.blocks.get(&key) {
+            Some(block) => block.clone(),
+            None => {
+                let block = Arc::new(Self::decode_block(mem, pc)?);
+                if pc >= 0x8000 {
+                    for page in block.pages.0..=block.pages.1 {
+                        self.code_pages[page] = true;
+                    }
+                }
+                self.blocks.insert(key, block.clone());
+                block
+            }
+        };
+        let cached = block.instructions[0];
+        self.current = Some((block, 1));
+        Some(cached)
+    }
+
+    fn decode_block<M: Memory>(mem: &M, start: u16) -> Option<Block> {
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        while instructions.len() < MAX_BLOCK_LEN {
+            let mut len = 0;
+            let Ok(instruction) = Disassembler::decode(|| {
+                len += 1;
+                mem.get(addr.wrapping_add(len - 1))
+            }) else {
+                break;
+            };
+            // every byte has to come from the same region, so a bank switch can't split it
+            let last = addr.wrapping_add(len - 1);
+            if region(last) != region(start) || last < addr {
+                break;
+            }
+            instructions.push(CachedInstruction {
+                addr,
+                len,
+                instruction,
+            });
+            addr = addr.wrapping_add(len);
+            if ends_block(&instruction) {
+                break;
+            }
+        }
+        let last = instructions.last()?;
+        Some(Block {
+            pages: (page(start), page(last.addr + last.len - 1)),
+            instructions,
+        })
+    }
+
+    // called for every write by the CPU
+    pub fn write(&mut self, addr: u16) {
+        if addr < 0x8000 {
+            // MBC register, the code being executed might get banked out
+            self.current = None;
+            return;
+        }
+        if addr == 0xFF70 {
+            // SVBK, 0xD000-0xDFFF and its echo now show another bank
+            self.drop_pages(0xD0..=0xDF);
+            return;
+        }
+        let page = page(addr);
+        self.drop_pages(page..=page);
+    }
+
+    fn drop_pages(&mut self, pages: RangeInclusive<usize>) {
+        if !self.code_pages[pages.clone()].contains(&true) {
+            return;
+        }
+        self.blocks
+            .retain(|_, block| block.pages.1 < *pages.start() || block.pages.0 > *pages.end());
+        self.code_pages[pages].fill(false);
+        self.current = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code_pages = [false; 256];
+        self.current = None;
+    }
+}
@@ -3,19 +3,50 @@ use crate::arithmetic::{
     op_res, op_rl, op_rlc, op_rr, op_rrc, op_sbc, op_set, op_sla, op_sra, op_srl, op_sub, op_swap,
     op_xor,
 };
+use crate::block_cache::BlockCache;
 use crate::disassembler::Disassembler;
+use crate::emulator::CYCLES_PER_FRAME;
+use crate::error::Error;
 use crate::isa::{
     ArithmeticInstruction, BitInstruction, Condition, Instruction, JumpInstruction,
     LoadInstruction, MiscInstruction, StackInstruction,
 };
-use crate::memory::{Interrupt, Mbc, Memory, RegisterPairValue};
+use crate::memory::{Interrupt, Memory, RegisterPairValue};
+use crate::rewind::{Rewind, RewindConfig};
+use crate::savestate::{self, StateReader, StateWriter};
 use crate::ControlMsg;
 use crate::Register;
 use crate::{Flags, RegisterPair, RegisterPairMem, RegisterPairStk};
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+// how instructions are fetched and decoded. The block cache skips decoding ROM and RAM code
+// that ran before, the results are the same as with the interpreter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Engine {
+    #[default]
+    Interpreter,
+    BlockCache,
+}
+
+// an emulation fault that stopped the CPU. The rest of the system keeps running, a reset clears it
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub pc: u16,
+    pub error: Error,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CPU locked up at {:#06X}: {}", self.pc, self.error)
+    }
+}
+
 pub struct Cpu<M: Memory> {
     af: RegisterPairValue,
     bc: RegisterPairValue,
@@ -24,15 +55,26 @@ pub struct Cpu<M: Memory> {
     pub sp: RegisterPairValue,
     pub pc: RegisterPairValue,
     pub mem: M,
-    disassembler: Disassembler,
     pub ime: bool, // interrupt master enable
-    stall: usize,
+    stall: usize,   // machine cycles the last step took, minus the one it was started in
+    cycles: usize,  // machine cycles taken by the current step
     pub(crate) last_cycle: Instant,
     pub recv: Receiver<ControlMsg>,
     halted: bool,
+    halt_bug: bool, // the next opcode fetch doesn't increment PC
+    stopped: bool,
     terminate: bool,
-    di_ctr: u8, // delay di instruction
     ei_ctr: u8, // delay ei instruction
+    engine: Engine,
+    block_cache: BlockCache,
+    fault: Option<Fault>,
+    pub fault_status: Arc<Mutex<Option<Fault>>>, // shared with the frontend
+    break_on_illegal: bool,
+    paused: bool, // the run loop doesn't advance the system
+    pub rom_path: Option<PathBuf>, // save state slots are kept next to it
+    rewind: Option<Rewind>,
+    rewinding: bool,      // the rewind key is held down
+    rewind_cycles: usize, // machine cycles since the last snapshot or step back
 }
 
 impl<M> Cpu<M>
@@ -48,15 +90,26 @@ where
             sp: RegisterPairValue::from(0xFFFE),
             pc: RegisterPairValue::from(0x0100),
             mem,
-            disassembler: Disassembler::new(),
             ime: false,
             stall: 0,
+            cycles: 0,
             last_cycle: Instant::now(),
             recv,
             halted: false,
+            halt_bug: false,
+            stopped: false,
             terminate: false,
-            di_ctr: 0,
             ei_ctr: 0,
+            engine: Engine::Interpreter,
+            block_cache: BlockCache::new(),
+            fault: None,
+            fault_status: Arc::new(Mutex::new(None)),
+            break_on_illegal: false,
+            paused: false,
+            rom_path: None,
+            rewind: None,
+            rewinding: false,
+            rewind_cycles: 0,
         }
     }
     
@@ -70,8 +123,34 @@ where
         self.ime = false;
         self.stall = 0;
         self.halted = false;
-        self.di_ctr = 0;
+        self.halt_bug = false;
+        self.stopped = false;
         self.ei_ctr = 0;
+        self.block_cache.clear();
+        self.fault = None;
+        *self.fault_status.lock().unwrap() = None;
+        self.paused = false;
+    }
+
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    fn set_fault(&mut self, pc: u16, error: Error) {
+        let fault = Fault { pc, error };
+        warn!("{}", fault);
+        *self.fault_status.lock().unwrap() = Some(fault.clone());
+        self.fault = Some(fault);
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        info!("Switching to {:?}", engine);
+        self.engine = engine;
+        self.block_cache.clear();
     }
 
     pub fn register(&self, reg_id: Register) -> u8 {
@@ -107,6 +186,8 @@ where
         }
     }
 
+    // HL+ and HL- are incremented or decremented on the bus during the access that follows,
+    // which corrupts OAM like INC HL and DEC HL do
     pub fn register_pair_mem(&mut self, reg_pair_id: RegisterPairMem) -> u16 {
         match reg_pair_id {
             RegisterPairMem::BC => self.bc.as_u16(),
@@ -114,11 +195,13 @@ where
             RegisterPairMem::HLI => {
                 let res = self.hl.as_u16();
                 self.hl = RegisterPairValue::from(res.wrapping_add(1));
+                self.mem.inc_dec_bus(res);
                 res
             }
             RegisterPairMem::HLD => {
                 let res = self.hl.as_u16();
                 self.hl = RegisterPairValue::from(res.wrapping_sub(1));
+                self.mem.inc_dec_bus(res);
                 res
             }
         }
@@ -166,6 +249,12 @@ where
                 self.control_message(msg);
             }
 
+            if self.paused {
+                std::thread::sleep(Duration::from_millis(10));
+                next_cycle_time = Instant::now();
+                continue;
+            }
+
             // Execute one machine cycle
             self.cycle();
 
@@ -183,23 +272,91 @@ where
         }
     }
 
+    // one machine cycle. A whole instruction is executed in the cycle it starts in, with the memory
+    // ticked between its bus accesses, and the following cycles are stalled to keep the pace
     pub fn cycle(&mut self) {
-        puffin::profile_function!();
+        profile_function!();
         self.last_cycle = Instant::now();
-        if self.di_ctr == 1 {
-            self.ime = false;
+        if self.stall > 0 {
+            self.stall -= 1;
+        } else {
+            self.stall = self.step() - 1;
         }
+        if self.rewind.is_some() {
+            self.rewind_cycle();
+        }
+    }
+
+    pub fn set_rewind(&mut self, config: Option<RewindConfig>) {
+        self.rewind = config.map(Rewind::new);
+        self.rewind_cycles = 0;
+    }
+
+    // takes a snapshot every few frames. While rewinding, steps back a snapshot every frame
+    // instead, so rewinding goes faster than playing by the snapshot interval
+    fn rewind_cycle(&mut self) {
+        let Some(rewind) = &self.rewind else {
+            return;
+        };
+        self.rewind_cycles += 1;
+        let interval = if self.rewinding {
+            CYCLES_PER_FRAME
+        } else {
+            CYCLES_PER_FRAME * rewind.config().interval.max(1) as usize
+        };
+        if self.rewind_cycles < interval {
+            return;
+        }
+        self.rewind_cycles = 0;
+        if !self.rewinding {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        } else if let Some(state) = self.rewind.as_mut().unwrap().step_back() {
+            if let Err(error) = self.load_state(&state) {
+                warn!("Unable to rewind: {}", error);
+            }
+        }
+    }
+
+    // executes one instruction, interrupt dispatch or halted cycle and returns the machine cycles
+    // it took
+    pub fn step(&mut self) -> usize {
+        self.cycles = 0;
         if self.ei_ctr == 1 {
             self.ime = true;
         }
-        self.di_ctr = self.di_ctr.saturating_sub(1);
         self.ei_ctr = self.ei_ctr.saturating_sub(1);
 
-        if self.stall > 0 {
-            self.stall -= 1;
-        } else if !self.halted {
-            let (instruction, new_pc) = self.disassembler.disassemble(&self.mem, self.pc.as_u16());
-            self.pc = RegisterPairValue::from(new_pc);
+        if self.stopped {
+            // the system clock is stopped until a button is pressed
+            if self.mem.button_pressed() {
+                info!("Leaving STOP mode");
+                self.stopped = false;
+            }
+            return 1;
+        }
+
+        if self.fault.is_some() {
+            self.tick();
+        } else if self.halted {
+            self.tick();
+            if self.pending_interrupts() != 0 {
+                self.halted = false;
+            }
+        } else if self.ime && self.pending_interrupts() != 0 {
+            self.dispatch_interrupt();
+        } else {
+            let pc = self.pc.as_u16();
+            let instruction = match self.cached_instruction() {
+                Some(instruction) => instruction,
+                None => match Disassembler::decode(|| self.fetch()) {
+                    Ok(instruction) => instruction,
+                    Err(error) => {
+                        self.set_fault(pc, error);
+                        return self.cycles;
+                    }
+                },
+            };
             match instruction {
                 Instruction::Arithmetic(x) => self.eval_arithmetic(x),
                 Instruction::Bit(x) => self.eval_bit(x),
@@ -209,71 +366,94 @@ where
                 Instruction::Misc(x) => self.eval_misc(x),
             }
         }
-        self.mem.cycle();
-        self.handle_interrupt();
+        self.cycles
     }
 
-    fn handle_interrupt(&mut self) {
-        if !self.ime && !self.halted {
-            return;
+    // takes the instruction at PC from the block cache if it's enabled. The opcode and immediate
+    // fetches aren't done, but still take their cycles
+    fn cached_instruction(&mut self) -> Option<Instruction> {
+        // a DMA transfer changes what the CPU reads outside HRAM, from the first cycle it has the
+        // bus. One that is starting can get it before the operands are read
+        if self.engine != Engine::BlockCache
+            || self.halt_bug
+            || self.mem.dma_active()
+            || self.mem.dma_starting()
+        {
+            return None;
         }
-        let triggered = self.mem.enabled_interrupts() & self.mem.requested_interrupts();
-        if triggered == 0 {
-            return;
+        let cached = self.block_cache.next(&self.mem, self.pc.as_u16())?;
+        for _ in 0..cached.len {
+            self.tick();
         }
-        self.halted = false;
-        if !self.ime {
-            return;
+        self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(cached.len));
+        Some(cached.instruction)
+    }
+
+    // every bus access takes one machine cycle, the rest of the system runs after it
+    fn tick(&mut self) {
+        self.mem.cycle();
+        self.cycles += 1;
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.mem.get(addr);
+        self.tick();
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if self.engine == Engine::BlockCache {
+            self.block_cache.write(addr);
         }
-        let requested = self.mem.requested_interrupts();
-        let enabled = self.mem.enabled_interrupts();
-        let interrupt = Interrupt::from(requested & enabled); // todo priority?
+        self.mem.write(addr, value);
+        self.tick();
+    }
+
+    // reads the byte at PC and increments it, unless the HALT bug is triggered
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.pc.as_u16());
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(1));
+        }
+        value
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.mem.enabled_interrupts() & self.mem.requested_interrupts() & 0x1F
+    }
 
+    // takes five machine cycles: two wait states, two for pushing PC and one for setting PC
+    fn dispatch_interrupt(&mut self) {
         self.ime = false;
-        self.push(self.pc.as_u16());
+        let mut pc = self.pc.as_u16();
+        if self.halt_bug {
+            // EI followed by HALT with an interrupt pending: the handler returns to the HALT
+            self.halt_bug = false;
+            pc = pc.wrapping_sub(1);
+        }
+        self.tick();
+        self.tick();
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(1));
+        self.write(self.sp.as_u16(), (pc >> 8) as u8);
+        // the interrupt is only chosen after the high byte of PC has been pushed, which can
+        // overwrite IE. If nothing is pending anymore, the dispatch is cancelled and jumps to 0
+        let interrupt = Interrupt::pending(self.pending_interrupts()).next();
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(1));
+        self.write(self.sp.as_u16(), pc as u8);
         match interrupt {
-            Interrupt::VBlank => {
-                self.mem.clear_requested_interrupt(Interrupt::VBlank);
-                self.pc = RegisterPairValue::from(0x0040);
-            }
-            Interrupt::LcdStat => {
-                debug!(
-                    "Requested interrupts: {:#08b}, enabled: {:#08b}",
-                    requested, enabled
-                );
-                debug!("Handling LCD Stat interrupt");
-                self.mem.clear_requested_interrupt(Interrupt::LcdStat);
-                self.pc = RegisterPairValue::from(0x0048);
-            }
-            Interrupt::Timer => {
-                debug!(
-                    "Requested interrupts: {:#08b}, enabled: {:#08b}",
-                    requested, enabled
-                );
-                debug!("Handling Timer interrupt");
-                self.mem.clear_requested_interrupt(Interrupt::Timer);
-                self.pc = RegisterPairValue::from(0x0050);
-            }
-            Interrupt::Serial => {
-                debug!(
-                    "Requested interrupts: {:#08b}, enabled: {:#08b}",
-                    requested, enabled
-                );
-                info!("Handling Serial interrupt");
-                self.mem.clear_requested_interrupt(Interrupt::Serial);
-                self.pc = RegisterPairValue::from(0x0058);
-            }
-            Interrupt::Joypad => {
-                debug!(
-                    "Requested interrupts: {:#08b}, enabled: {:#08b}",
-                    requested, enabled
-                );
-                debug!("Handling Joypad interrupt");
-                self.mem.clear_requested_interrupt(Interrupt::Joypad);
-                self.pc = RegisterPairValue::from(0x0060);
+            Some(interrupt) => {
+                debug!("Handling {:?} interrupt", interrupt);
+                self.mem.clear_requested_interrupt(interrupt);
+                self.pc = RegisterPairValue::from(interrupt.vector());
+            }
+            None => {
+                debug!("Interrupt dispatch cancelled by IE write");
+                self.pc = RegisterPairValue::from(0x0000);
             }
         }
-        self.stall += 4; // indeed 4 full cycles because we don't fetch an instruction
+        self.tick();
     }
 
     fn eval_arithmetic(&mut self, instruction: ArithmeticInstruction) {
@@ -286,15 +466,13 @@ where
             }
             ArithmeticInstruction::AdcAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_adc(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AdcAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_adc(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AddAR8(reg) => {
                 let a = self.af.high();
@@ -303,15 +481,13 @@ where
             }
             ArithmeticInstruction::AddAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_add(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AddAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_add(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AndAR8(reg) => {
                 let a = self.af.high();
@@ -320,15 +496,13 @@ where
             }
             ArithmeticInstruction::AndAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_and(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AndAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_and(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::CpAR8(reg) => {
                 let a = self.af.high();
@@ -337,33 +511,31 @@ where
             }
             ArithmeticInstruction::CpAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 op_cp(a, b, &mut flags);
-                self.stall = 1;
             }
             ArithmeticInstruction::CpAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 op_cp(a, b, &mut flags);
-                self.stall = 1;
             }
             ArithmeticInstruction::DecR8(reg) => {
                 let a = self.register(reg);
                 *self.register_mut(reg) = op_dec(a, &mut flags);
             }
             ArithmeticInstruction::DecMemHL => {
-                let a = self.mem.get(self.hl.as_u16());
-                self.mem.update(self.hl.as_u16(), || op_dec(a, &mut flags));
-                self.stall = 2;
+                let a = self.read(self.hl.as_u16());
+                let value = op_dec(a, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             ArithmeticInstruction::IncR8(reg) => {
                 let a = self.register(reg);
                 *self.register_mut(reg) = op_inc(a, &mut flags);
             }
             ArithmeticInstruction::IncMemHL => {
-                let a = self.mem.get(self.hl.as_u16());
-                self.mem.update(self.hl.as_u16(), || op_inc(a, &mut flags));
-                self.stall = 2;
+                let a = self.read(self.hl.as_u16());
+                let value = op_inc(a, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             ArithmeticInstruction::OrAR8(reg) => {
                 let a = self.af.high();
@@ -372,15 +544,13 @@ where
             }
             ArithmeticInstruction::OrAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_or(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::OrAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_or(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::SbcAR8(reg) => {
                 let a = self.af.high();
@@ -389,15 +559,13 @@ where
             }
             ArithmeticInstruction::SbcAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_sbc(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::SbcAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_sbc(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::SubAR8(reg) => {
                 let a = self.af.high();
@@ -406,15 +574,13 @@ where
             }
             ArithmeticInstruction::SubAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_sub(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::SubAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_sub(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::XorAR8(reg) => {
                 let a = self.af.high();
@@ -423,31 +589,31 @@ where
             }
             ArithmeticInstruction::XorAMemHL => {
                 let a = self.af.high();
-                let b = self.mem.get(self.hl.as_u16());
+                let b = self.read(self.hl.as_u16());
                 self.af.set_high(op_xor(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::XorAN8(imm) => {
                 let a = self.af.high();
                 let b = imm;
                 self.af.set_high(op_xor(a, b, &mut flags));
-                self.stall = 1;
             }
             ArithmeticInstruction::AddHLR16(reg) => {
                 let a = self.hl.as_u16();
                 let b = self.register_pair(reg);
                 self.hl = RegisterPairValue::from(op_add16(a, b, &mut flags));
-                self.stall = 1;
+                self.tick();
             }
             ArithmeticInstruction::DecR16(reg) => {
                 let a = self.register_pair(reg);
                 *self.register_pair_mut(reg) = RegisterPairValue::from(op_dec16(a));
-                self.stall = 1;
+                self.mem.inc_dec_bus(a);
+                self.tick();
             }
             ArithmeticInstruction::IncR16(reg) => {
                 let a = self.register_pair(reg);
                 *self.register_pair_mut(reg) = RegisterPairValue::from(op_inc16(a));
-                self.stall = 1;
+                self.mem.inc_dec_bus(a);
+                self.tick();
             }
         }
         self.af.set_low(flags.bits());
@@ -455,49 +621,44 @@ where
 
     fn eval_bit(&mut self, instruction: BitInstruction) {
         let mut flags = self.af.flags();
-        self.stall = 1;
         match instruction {
             BitInstruction::Bit(a, reg) => {
                 op_bit(a, self.register(reg), &mut flags);
             }
             BitInstruction::BitMemHL(a) => {
-                op_bit(a, self.mem.get(self.hl.as_u16()), &mut flags);
-                self.stall = 2;
+                op_bit(a, self.read(self.hl.as_u16()), &mut flags);
             }
             BitInstruction::Res(a, reg) => {
                 *self.register_mut(reg) = op_res(a, self.register(reg));
             }
             BitInstruction::ResMemHL(a) => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem.update(self.hl.as_u16(), || op_res(a, prev));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_res(a, prev);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Set(a, reg) => {
                 *self.register_mut(reg) = op_set(a, self.register(reg));
             }
             BitInstruction::SetMemHL(a) => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem.update(self.hl.as_u16(), || op_set(a, prev));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_set(a, prev);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Swap(reg) => {
                 *self.register_mut(reg) = op_swap(self.register(reg), &mut flags);
-                self.stall = 1;
             }
             BitInstruction::SwapMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_swap(prev, &mut flags));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_swap(prev, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Rl(reg) => {
                 *self.register_mut(reg) = op_rl(self.register(reg), &mut flags, false);
             }
             BitInstruction::RlMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_rl(prev, &mut flags, false));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_rl(prev, &mut flags, false);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Rla => {
                 *self.register_mut(Register::A) =
@@ -507,10 +668,9 @@ where
                 *self.register_mut(reg) = op_rlc(self.register(reg), &mut flags, false);
             }
             BitInstruction::RlcMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_rlc(prev, &mut flags, false));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_rlc(prev, &mut flags, false);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Rlca => {
                 *self.register_mut(Register::A) =
@@ -520,11 +680,9 @@ where
                 *self.register_mut(reg) = op_rr(self.register(reg), &mut flags);
             }
             BitInstruction::RrMemHL => {
-                println!("Working with value {:#04X}", self.mem.get(self.hl.as_u16()));
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_rr(prev, &mut flags));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_rr(prev, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Rra => {
                 *self.register_mut(Register::A) = op_rr(self.register(Register::A), &mut flags);
@@ -533,10 +691,9 @@ where
                 *self.register_mut(reg) = op_rrc(self.register(reg), &mut flags, false);
             }
             BitInstruction::RrcMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_rrc(prev, &mut flags, false));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_rrc(prev, &mut flags, false);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Rrca => {
                 *self.register_mut(Register::A) =
@@ -546,28 +703,25 @@ where
                 *self.register_mut(reg) = op_sla(self.register(reg), &mut flags);
             }
             BitInstruction::SlaMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_sla(prev, &mut flags));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_sla(prev, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Sra(reg) => {
                 *self.register_mut(reg) = op_sra(self.register(reg), &mut flags);
             }
             BitInstruction::SraMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_sra(prev, &mut flags));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_sra(prev, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
             BitInstruction::Srl(reg) => {
                 *self.register_mut(reg) = op_srl(self.register(reg), &mut flags);
             }
             BitInstruction::SrlMemHL => {
-                let prev = self.mem.get(self.hl.as_u16());
-                self.mem
-                    .update(self.hl.as_u16(), || op_srl(prev, &mut flags));
-                self.stall = 3;
+                let prev = self.read(self.hl.as_u16());
+                let value = op_srl(prev, &mut flags);
+                self.write(self.hl.as_u16(), value);
             }
         }
         self.af.set_low(flags.bits());
@@ -580,89 +734,76 @@ where
             }
             LoadInstruction::LdR8N8(reg, imm) => {
                 *self.register_mut(reg) = imm;
-                self.stall = 1;
             }
             LoadInstruction::LdR16N16(reg, imm) => {
                 *self.register_pair_mut(reg) = RegisterPairValue::from(imm);
-                self.stall = 2;
             }
             LoadInstruction::LdMemHLR8(reg) => {
-                let val = self.register(reg);
-                self.mem.update(self.hl.as_u16(), || val);
-                self.stall = 1;
+                let value = self.register(reg);
+                self.write(self.hl.as_u16(), value);
             }
             LoadInstruction::LdMemHLN8(imm) => {
-                self.mem.update(self.hl.as_u16(), || imm);
-                self.stall = 2;
+                self.write(self.hl.as_u16(), imm);
             }
             LoadInstruction::LdR8MemHL(reg) => {
-                *self.register_mut(reg) = self.mem.get(self.hl.as_u16());
-                self.stall = 1;
+                *self.register_mut(reg) = self.read(self.hl.as_u16());
             }
             LoadInstruction::LdMemR16A(reg) => {
                 let addr = self.register_pair_mem(reg);
-                self.mem.update(addr, || self.af.high());
-                self.stall = 1;
+                self.write(addr, self.af.high());
             }
             LoadInstruction::LdMemN16A(addr) => {
-                self.mem.update(addr, || self.af.high());
-                self.stall = 4;
+                self.write(addr, self.af.high());
             }
             LoadInstruction::LdhMemN16A(addr) => {
-                self.mem.update(0xFF00 + addr, || self.af.high());
-                self.stall = 2;
+                self.write(0xFF00 + addr, self.af.high());
             }
             LoadInstruction::LdhMemCA => {
-                self.mem
-                    .update(0xFF00 + self.bc.low() as u16, || self.af.high());
-                self.stall = 1;
+                self.write(0xFF00 + self.bc.low() as u16, self.af.high());
             }
             LoadInstruction::LdAMemR16(reg) => {
                 let addr = self.register_pair_mem(reg);
-                self.af.set_high(self.mem.get(addr));
-                self.stall = 1;
+                let value = self.read(addr);
+                self.af.set_high(value);
             }
             LoadInstruction::LdAMemN16(addr) => {
-                self.af.set_high(self.mem.get(addr));
-                self.stall = 3;
+                let value = self.read(addr);
+                self.af.set_high(value);
             }
             LoadInstruction::LdhAMemN16(addr) => {
-                self.af.set_high(self.mem.get(0xFF00 + addr));
-                self.stall = 1;
+                let value = self.read(0xFF00 + addr);
+                self.af.set_high(value);
             }
             LoadInstruction::LdhAMemC => {
-                self.af
-                    .set_high(self.mem.get(0xFF00 + self.bc.low() as u16));
-                self.stall = 1;
+                let value = self.read(0xFF00 + self.bc.low() as u16);
+                self.af.set_high(value);
             }
             LoadInstruction::LdMemHLIA => {
-                self.mem.update(self.hl.as_u16(), || self.af.high());
+                let value = self.af.high();
+                self.write(self.hl.as_u16(), value);
                 self.hl = RegisterPairValue::from(self.hl.as_u16().wrapping_add(1));
-                self.stall = 1;
             }
             LoadInstruction::LdMemHLDA => {
-                self.mem.update(self.hl.as_u16(), || self.af.high());
+                let value = self.af.high();
+                self.write(self.hl.as_u16(), value);
                 self.hl = RegisterPairValue::from(self.hl.as_u16().wrapping_sub(1));
-                self.stall = 1;
             }
             LoadInstruction::LdAMemHLI => {
-                self.af.set_high(self.mem.get(self.hl.as_u16()));
+                let value = self.read(self.hl.as_u16());
+                self.af.set_high(value);
                 self.hl = RegisterPairValue::from(self.hl.as_u16().wrapping_add(1));
-                self.stall = 1;
             }
             LoadInstruction::LdAMemHLD => {
-                self.af.set_high(self.mem.get(self.hl.as_u16()));
+                let value = self.read(self.hl.as_u16());
+                self.af.set_high(value);
                 self.hl = RegisterPairValue::from(self.hl.as_u16().wrapping_sub(1));
-                self.stall = 1;
             }
             LoadInstruction::LdhAMemN8(addr) => {
-                let val = self.mem.get(0xFF00 + addr as u16);
-                self.af.set_high(val);
-                self.stall = 2;
+                let value = self.read(0xFF00 + addr as u16);
+                self.af.set_high(value);
             }
             LoadInstruction::LdhMemN8A(addr) => {
-                self.mem.update(0xFF00 + addr as u16, || self.af.high());
-                self.stall = 2;
+                self.write(0xFF00 + addr as u16, self.af.high());
             }
         }
     }
@@ -677,33 +818,33 @@ where
     }
 
     fn push(&mut self, value: u16) {
-        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(2));
-        self.mem.write(self.sp.as_u16(), value as u8);
-        self.mem
-            .write(self.sp.as_u16().wrapping_add(1), (value >> 8) as u8);
+        // the high byte is pushed first
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(1));
+        self.write(self.sp.as_u16(), (value >> 8) as u8);
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(1));
+        self.write(self.sp.as_u16(), value as u8);
     }
 
     fn pop(&mut self) -> u16 {
-        let lo = self.mem.get(self.sp.as_u16());
-        let hi = self.mem.get(self.sp.as_u16().wrapping_add(1));
-        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_add(2));
+        let lo = self.read(self.sp.as_u16());
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_add(1));
+        let hi = self.read(self.sp.as_u16());
+        self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_add(1));
         (hi as u16) << 8 | lo as u16
     }
 
     fn eval_jump(&mut self, instruction: JumpInstruction) {
         match instruction {
             JumpInstruction::CallN16(imm) => {
+                self.tick();
                 self.push(self.pc.as_u16());
                 self.pc = RegisterPairValue::from(imm);
-                self.stall = 5;
             }
             JumpInstruction::CallCCN16(cond, imm) => {
                 if self.eval_cond(cond) {
+                    self.tick();
                     self.push(self.pc.as_u16());
                     self.pc = RegisterPairValue::from(imm);
-                    self.stall = 5;
-                } else {
-                    self.stall = 2;
                 }
             }
             JumpInstruction::JpHL => {
@@ -711,49 +852,45 @@ where
             }
             JumpInstruction::JpN16(imm) => {
                 self.pc = RegisterPairValue::from(imm);
-                self.stall = 3;
+                self.tick();
             }
             JumpInstruction::JpCCN16(cond, imm) => {
                 if self.eval_cond(cond) {
                     self.pc = RegisterPairValue::from(imm);
-                    self.stall = 3;
-                } else {
-                    self.stall = 2;
+                    self.tick();
                 }
             }
             JumpInstruction::JrN8(imm) => {
                 self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(imm as u16));
-                self.stall = 2;
+                self.tick();
             }
             JumpInstruction::JrCCN8(cond, imm) => {
                 if self.eval_cond(cond) {
                     self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(imm as u16));
-                    self.stall = 2;
-                } else {
-                    self.stall = 1;
+                    self.tick();
                 }
             }
             JumpInstruction::RetCC(cond) => {
+                // the condition is checked in an extra cycle
+                self.tick();
                 if self.eval_cond(cond) {
                     self.pc = RegisterPairValue::from(self.pop());
-                    self.stall = 4;
-                } else {
-                    self.stall = 1;
+                    self.tick();
                 }
             }
             JumpInstruction::Ret => {
                 self.pc = RegisterPairValue::from(self.pop());
-                self.stall = 3;
+                self.tick();
             }
             JumpInstruction::Reti => {
                 self.pc = RegisterPairValue::from(self.pop());
+                self.tick();
                 self.ime = true;
-                self.stall = 3;
             }
             JumpInstruction::Rst(vec) => {
+                self.tick();
                 self.push(self.pc.as_u16());
                 self.pc = RegisterPairValue::from(vec);
-                self.stall = 3;
             }
         }
     }
@@ -768,7 +905,7 @@ where
                     &mut flags,
                 ));
                 self.af.set_low(flags.bits());
-                self.stall = 1;
+                self.tick();
             }
             StackInstruction::AddSPE8(imm) => {
                 let imm = imm as i16 as u16;
@@ -783,29 +920,26 @@ where
                 );
                 self.af.set_low(flags.bits());
                 self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_add(imm));
-                self.stall = 3;
+                self.tick();
+                self.tick();
             }
             StackInstruction::DecSP => {
                 self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_sub(1));
-                self.stall = 1;
+                self.tick();
             }
             StackInstruction::IncSP => {
                 self.sp = RegisterPairValue::from(self.sp.as_u16().wrapping_add(1));
-                self.stall = 1;
+                self.tick();
             }
             StackInstruction::LdSPN16(imm) => {
                 self.sp = RegisterPairValue::from(imm);
-                self.stall = 2;
             }
             StackInstruction::LdMemN16SP(imm) => {
-                let addr = imm;
-                self.mem.update(addr, || self.sp.low());
-                self.mem.update(addr + 1, || self.sp.high());
-                self.stall = 4;
+                self.write(imm, self.sp.low());
+                self.write(imm.wrapping_add(1), self.sp.high());
             }
             StackInstruction::LdHLSPPlusE8(imm) => {
                 self.hl = RegisterPairValue::from(self.sp.as_u16().wrapping_add(imm as u16));
-                self.stall = 2;
                 let mut flags = Flags::empty();
                 flags.set(Flags::ZERO, false);
                 flags.set(Flags::SUBTRACT, false);
@@ -818,10 +952,11 @@ where
                     (self.sp.low() as u16) + ((imm as u8) as u16) > 0x00FF,
                 );
                 self.af.set_low(flags.bits());
+                self.tick();
             }
             StackInstruction::LdSPHL => {
                 self.sp = self.hl;
-                self.stall = 1;
+                self.tick();
             }
             StackInstruction::PopAF => {
                 self.af = RegisterPairValue::from(self.pop());
@@ -834,18 +969,17 @@ where
                 );
                 flags.set(Flags::CARRY, self.af.low() & Flags::CARRY.bits() != 0);
                 self.af.set_low(flags.bits());
-                self.stall = 2;
             }
             StackInstruction::PopR16(reg) => {
                 *self.register_pair_stk_mut(reg) = RegisterPairValue::from(self.pop());
-                self.stall = 2;
             }
             StackInstruction::PushAF => {
                 // todo: why is this variant required?
+                self.tick();
                 self.push(self.af.as_u16());
-                self.stall = 3;
             }
             StackInstruction::PushR16(reg) => {
+                self.tick();
                 match reg {
                     RegisterPairStk::BC => {
                         self.push(self.bc.as_u16());
@@ -860,7 +994,6 @@ where
                         self.push(self.af.as_u16());
                     }
                 }
-                self.stall = 3;
             }
         }
     }
@@ -911,7 +1044,9 @@ where
                 self.af.set_low(flags.bits());
             }
             MiscInstruction::Di => {
-                self.di_ctr = 2;
+                // unlike EI, DI takes effect immediately and cancels a pending EI
+                self.ime = false;
+                self.ei_ctr = 0;
                 info!("Disabling interrupts...")
             }
             MiscInstruction::Ei => {
@@ -919,8 +1054,14 @@ where
                 info!("Enabling interrupts...")
             }
             MiscInstruction::Halt => {
-                self.halted = true;
-                info!("Halting CPU...")
+                if !self.ime && self.pending_interrupts() != 0 {
+                    // HALT bug: the CPU doesn't halt and reads the next byte twice
+                    debug!("HALT bug triggered");
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                    info!("Halting CPU...")
+                }
             }
             MiscInstruction::Nop => {}
             MiscInstruction::Scf => {
@@ -930,11 +1071,115 @@ where
                 flags.set(Flags::HALF_CARRY, false);
                 self.af.set_low(flags.bits());
             }
-            MiscInstruction::Stop => {
-                self.halted = true; // TODO: not sure if this is correct...
-                panic!("Stopping CPU...")
+            MiscInstruction::Stop => self.stop(),
+            MiscInstruction::Illegal(opcode) => {
+                // the CPU stops fetching and ignores interrupts until it's reset
+                let pc = self.pc.as_u16().wrapping_sub(1);
+                self.set_fault(pc, Error::IllegalOpcode(opcode));
+                self.paused = self.break_on_illegal;
+            }
+        }
+    }
+
+    // STOP is a two byte instruction in most cases, the second byte is skipped here
+    fn stop(&mut self) {
+        let interrupt_pending = self.pending_interrupts() != 0;
+        if self.mem.button_pressed() {
+            if !interrupt_pending {
+                info!("STOP with button held, halting CPU...");
+                self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(1));
+                self.halted = true;
             }
+            return;
+        }
+        if !interrupt_pending {
+            self.pc = RegisterPairValue::from(self.pc.as_u16().wrapping_add(1));
+        }
+        if self.mem.stop() {
+            info!("Switched CPU speed");
+            for _ in 0..2050 {
+                self.tick();
+            }
+        } else {
+            info!("Entering STOP mode...");
+            self.stopped = true;
+        }
+    }
+
+    // the whole system, see savestate for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.section(b"CPU ");
+        for pair in [self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+            state.u16(pair.as_u16());
         }
+        state.bool(self.ime);
+        state.u8(self.ei_ctr);
+        state.bool(self.halted);
+        state.bool(self.halt_bug);
+        state.bool(self.stopped);
+        state.u32(self.stall as u32);
+        self.mem.save_state(&mut state);
+        state.finish()
+    }
+
+    // a corrupted state or one for another ROM leaves the machine as it was. Sections are loaded
+    // one after the other, if a later one is broken the earlier ones are undone from a snapshot
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut state = StateReader::new(data)?;
+        let backup = self.save_state();
+        if let Err(error) = self.load_sections(&mut state) {
+            // written by this build for this ROM, so it always loads
+            self.load_sections(&mut StateReader::new(&backup)?)?;
+            return Err(error);
+        }
+        self.block_cache.clear();
+        self.fault = None;
+        *self.fault_status.lock().unwrap() = None;
+        self.paused = false;
+        Ok(())
+    }
+
+    fn load_sections(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.mem.load_state(state)?;
+        state.section(b"CPU ")?;
+        self.af = RegisterPairValue::from(state.u16()? & 0xFFF0);
+        self.bc = RegisterPairValue::from(state.u16()?);
+        self.de = RegisterPairValue::from(state.u16()?);
+        self.hl = RegisterPairValue::from(state.u16()?);
+        self.sp = RegisterPairValue::from(state.u16()?);
+        self.pc = RegisterPairValue::from(state.u16()?);
+        self.ime = state.bool()?;
+        self.ei_ctr = state.u8()?;
+        self.halted = state.bool()?;
+        self.halt_bug = state.bool()?;
+        self.stopped = state.bool()?;
+        self.stall = if state.version() < 2 {
+            state.u8()? as usize
+        } else {
+            state.u32()? as usize
+        };
+        Ok(())
+    }
+
+    fn save_slot(&self, slot: u8) -> Result<(), Error> {
+        let Some(rom_path) = &self.rom_path else {
+            return Err(Error::UnhandledControlMsg(ControlMsg::SaveState(slot)));
+        };
+        let path = savestate::slot_path(rom_path, slot);
+        fs::write(&path, self.save_state())?;
+        info!("Saved state to {}", path.display());
+        Ok(())
+    }
+
+    fn load_slot(&mut self, slot: u8) -> Result<(), Error> {
+        let Some(rom_path) = &self.rom_path else {
+            return Err(Error::UnhandledControlMsg(ControlMsg::LoadState(slot)));
+        };
+        let path = savestate::slot_path(rom_path, slot);
+        self.load_state(&fs::read(&path)?)?;
+        info!("Loaded state from {}", path.display());
+        Ok(())
     }
 
     pub fn control_message(&mut self, msg: ControlMsg) {
@@ -944,7 +1189,35 @@ where
                 info!("Resetting CPU...");
                 self.reset();
             }
-            _ => self.mem.control_msg(msg),
+            ControlMsg::SetEngine(engine) => self.set_engine(engine),
+            ControlMsg::BreakOnIllegal(enable) => self.break_on_illegal = enable,
+            ControlMsg::Continue => self.paused = false,
+            ControlMsg::SaveState(slot) => {
+                if let Err(error) = self.save_slot(slot) {
+                    warn!("Unable to save state: {}", error);
+                }
+            }
+            ControlMsg::Rewind(held) => {
+                if let Some(rewind) = &mut self.rewind {
+                    if held {
+                        rewind.start();
+                    } else {
+                        rewind.stop();
+                    }
+                    self.rewinding = held;
+                }
+                self.rewind_cycles = 0;
+            }
+            ControlMsg::LoadState(slot) => {
+                if let Err(error) = self.load_slot(slot) {
+                    warn!("Unable to load state: {}", error);
+                }
+            }
+            _ => {
+                if let Err(error) = self.mem.control_msg(msg) {
+                    warn!("{}", error);
+                }
+            }
         }
     }
 }
@@ -2,9 +2,50 @@ use crate::isa::{
     ArithmeticInstruction, BitInstruction, Condition, Instruction, JumpInstruction,
     LoadInstruction, MiscInstruction, StackInstruction,
 };
-use crate::memory::Memory;
+use crate::error::Error;
 use crate::{Register, RegisterPair, RegisterPairMem, RegisterPairStk};
 use log::debug;
+use std::sync::OnceLock;
+
+// undefined opcodes, they decode to MiscInstruction::Illegal
+pub const ILLEGAL_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
+static DECODE_TABLE: OnceLock<DecodeTable> = OnceLock::new();
+
+// instruction templates for every opcode together with the number of immediate bytes that
+// follow it, the immediates in the templates are zero
+struct DecodeTable {
+    base: [Option<(Instruction, u8)>; 256],
+    prefixed: [Option<Instruction>; 256],
+}
+
+impl DecodeTable {
+    fn new() -> Self {
+        let base = std::array::from_fn(|opcode| {
+            let opcode = opcode as u8;
+            if opcode == 0xCB {
+                return None;
+            }
+            let mut fetched = 0;
+            let instruction = Disassembler::decode_bits(|| {
+                fetched += 1;
+                if fetched == 1 {
+                    opcode
+                } else {
+                    0
+                }
+            });
+            Some((instruction.ok()?, fetched - 1))
+        });
+        let prefixed = std::array::from_fn(|opcode| {
+            let mut bytes = [0xCB, opcode as u8].into_iter();
+            Disassembler::decode_bits(|| bytes.next().unwrap()).ok()
+        });
+        Self { base, prefixed }
+    }
+}
 
 pub struct Disassembler {
     cursor: usize,
@@ -21,19 +62,52 @@ impl Disassembler {
         Disassembler { cursor: 0 }
     }
 
-    pub fn disassemble<M>(&mut self, mem: &M, pc: u16) -> (Instruction, u16)
+    pub fn disassemble<M>(&mut self, mem: &M, pc: u16) -> Result<(Instruction, u16), Error>
     where
         M: crate::memory::Memory,
     {
         self.cursor = pc as usize;
-        let byte = self.nom(mem);
+        let instruction = Self::decode(|| {
+            self.cursor += 1;
+            mem.get((self.cursor - 1) as u16)
+        });
+        debug!("{:?}", instruction);
+        Ok((instruction?, self.cursor as u16))
+    }
+
+    // decodes one instruction, next is called once for every byte of it, in order. The CPU uses
+    // this to do the opcode and immediate fetches as timed bus reads
+    pub fn decode<F>(mut next: F) -> Result<Instruction, Error>
+    where
+        F: FnMut() -> u8,
+    {
+        let table = DECODE_TABLE.get_or_init(DecodeTable::new);
+        let opcode = next();
+        if opcode == 0xCB {
+            let opcode = next();
+            return table.prefixed[opcode as usize].ok_or(Error::InvalidOpcode(opcode));
+        }
+        let (template, operands) = table.base[opcode as usize].ok_or(Error::InvalidOpcode(opcode))?;
+        Ok(match operands {
+            0 => template,
+            1 => template.with_immediate(next() as u16),
+            _ => template.with_immediate(Self::nomnom(&mut next)),
+        })
+    }
+
+    // decodes one instruction by matching the bits of the opcode, this builds the decode table
+    pub fn decode_bits<F>(mut next: F) -> Result<Instruction, Error>
+    where
+        F: FnMut() -> u8,
+    {
+        let byte = next();
 
         let instruction = match Self::bits_tup(byte) {
             // Block 0
             (0, 0, 0, 0, 0, 0, 0, 0) => Instruction::Misc(MiscInstruction::Nop),
             (0, 0, a, b, 0, 0, 0, 1) => Instruction::Load(LoadInstruction::LdR16N16(
                 RegisterPair::from_bits(a, b),
-                self.nomnom(mem),
+                Self::nomnom(&mut next),
             )),
             (0, 0, a, b, 0, 0, 1, 0) => {
                 Instruction::Load(LoadInstruction::LdMemR16A(RegisterPairMem::from_bits(a, b)))
@@ -42,7 +116,7 @@ impl Disassembler {
                 Instruction::Load(LoadInstruction::LdAMemR16(RegisterPairMem::from_bits(a, b)))
             }
             (0, 0, 0, 0, 1, 0, 0, 0) => {
-                Instruction::Stack(StackInstruction::LdMemN16SP(self.nomnom(mem)))
+                Instruction::Stack(StackInstruction::LdMemN16SP(Self::nomnom(&mut next)))
             }
 
             (0, 0, a, b, 0, 0, 1, 1) => Instruction::Arithmetic(ArithmeticInstruction::IncR16(
@@ -57,19 +131,19 @@ impl Disassembler {
 
             (0, 0, 1, 1, 0, 1, 0, 0) => Instruction::Arithmetic(ArithmeticInstruction::IncMemHL),
             (0, 0, a, b, c, 1, 0, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::IncR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::IncR8(Register::from_bits(a, b, c)?))
             }
             (0, 0, 1, 1, 0, 1, 0, 1) => Instruction::Arithmetic(ArithmeticInstruction::DecMemHL),
             (0, 0, a, b, c, 1, 0, 1) => {
-                Instruction::Arithmetic(ArithmeticInstruction::DecR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::DecR8(Register::from_bits(a, b, c)?))
             }
 
             (0, 0, 1, 1, 0, 1, 1, 0) => {
-                Instruction::Load(LoadInstruction::LdMemHLN8(self.nom(mem)))
+                Instruction::Load(LoadInstruction::LdMemHLN8(next()))
             }
             (0, 0, a, b, c, 1, 1, 0) => Instruction::Load(LoadInstruction::LdR8N8(
-                Register::from_bits(a, b, c),
-                self.nom(mem),
+                Register::from_bits(a, b, c)?,
+                next(),
             )),
 
             (0, 0, 0, 0, 0, 1, 1, 1) => Instruction::Bit(BitInstruction::Rlca),
@@ -82,11 +156,11 @@ impl Disassembler {
             (0, 0, 1, 1, 1, 1, 1, 1) => Instruction::Misc(MiscInstruction::Ccf),
 
             (0, 0, 0, 1, 1, 0, 0, 0) => {
-                Instruction::Jump(JumpInstruction::JrN8(self.nom(mem) as i8))
+                Instruction::Jump(JumpInstruction::JrN8(next() as i8))
             }
             (0, 0, 1, a, b, 0, 0, 0) => Instruction::Jump(JumpInstruction::JrCCN8(
                 Condition::from_bits(a, b),
-                self.nom(mem) as i8,
+                next() as i8,
             )),
 
             (0, 0, 0, 1, 0, 0, 0, 0) => Instruction::Misc(MiscInstruction::Stop),
@@ -95,74 +169,74 @@ impl Disassembler {
             (0, 1, 1, 1, 0, 1, 1, 0) => Instruction::Misc(MiscInstruction::Halt),
 
             (0, 1, 1, 1, 0, a, b, c) => {
-                Instruction::Load(LoadInstruction::LdMemHLR8(Register::from_bits(a, b, c)))
+                Instruction::Load(LoadInstruction::LdMemHLR8(Register::from_bits(a, b, c)?))
             }
             (0, 1, a, b, c, 1, 1, 0) => {
-                Instruction::Load(LoadInstruction::LdR8MemHL(Register::from_bits(a, b, c)))
+                Instruction::Load(LoadInstruction::LdR8MemHL(Register::from_bits(a, b, c)?))
             }
             (0, 1, a, b, c, x, y, z) => Instruction::Load(LoadInstruction::LdR8R8(
-                Register::from_bits(a, b, c),
-                Register::from_bits(x, y, z),
+                Register::from_bits(a, b, c)?,
+                Register::from_bits(x, y, z)?,
             )),
 
             // Block 2
             (1, 0, 0, 0, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AddAMemHL),
             (1, 0, 0, 0, 0, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AddAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::AddAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 0, 0, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AdcAMemHL),
             (1, 0, 0, 0, 1, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AdcAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::AdcAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 0, 1, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::SubAMemHL),
             (1, 0, 0, 1, 0, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::SubAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::SubAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 0, 1, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::SbcAMemHL),
             (1, 0, 0, 1, 1, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::SbcAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::SbcAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 1, 0, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AndAMemHL),
             (1, 0, 1, 0, 0, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AndAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::AndAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 1, 0, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::XorAMemHL),
             (1, 0, 1, 0, 1, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::XorAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::XorAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 1, 1, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::OrAMemHL),
             (1, 0, 1, 1, 0, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::OrAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::OrAR8(Register::from_bits(a, b, c)?))
             }
             (1, 0, 1, 1, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::CpAMemHL),
             (1, 0, 1, 1, 1, a, b, c) => {
-                Instruction::Arithmetic(ArithmeticInstruction::CpAR8(Register::from_bits(a, b, c)))
+                Instruction::Arithmetic(ArithmeticInstruction::CpAR8(Register::from_bits(a, b, c)?))
             }
 
             // Block 3
             (1, 1, 0, 0, 0, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AddAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::AddAN8(next()))
             }
             (1, 1, 0, 0, 1, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AdcAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::AdcAN8(next()))
             }
             (1, 1, 0, 1, 0, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::SubAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::SubAN8(next()))
             }
             (1, 1, 0, 1, 1, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::SbcAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::SbcAN8(next()))
             }
             (1, 1, 1, 0, 0, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::AndAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::AndAN8(next()))
             }
             (1, 1, 1, 0, 1, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::XorAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::XorAN8(next()))
             }
             (1, 1, 1, 1, 0, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::OrAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::OrAN8(next()))
             }
             (1, 1, 1, 1, 1, 1, 1, 0) => {
-                Instruction::Arithmetic(ArithmeticInstruction::CpAN8(self.nom(mem)))
+                Instruction::Arithmetic(ArithmeticInstruction::CpAN8(next()))
             }
 
             (1, 1, 0, a, b, 0, 0, 0) => {
@@ -172,16 +246,16 @@ impl Disassembler {
             (1, 1, 0, 1, 1, 0, 0, 1) => Instruction::Jump(JumpInstruction::Reti),
             (1, 1, 0, a, b, 0, 1, 0) => Instruction::Jump(JumpInstruction::JpCCN16(
                 Condition::from_bits(a, b),
-                self.nomnom(mem),
+                Self::nomnom(&mut next),
             )),
-            (1, 1, 0, 0, 0, 0, 1, 1) => Instruction::Jump(JumpInstruction::JpN16(self.nomnom(mem))),
+            (1, 1, 0, 0, 0, 0, 1, 1) => Instruction::Jump(JumpInstruction::JpN16(Self::nomnom(&mut next))),
             (1, 1, 1, 0, 1, 0, 0, 1) => Instruction::Jump(JumpInstruction::JpHL),
             (1, 1, 0, a, b, 1, 0, 0) => Instruction::Jump(JumpInstruction::CallCCN16(
                 Condition::from_bits(a, b),
-                self.nomnom(mem),
+                Self::nomnom(&mut next),
             )),
             (1, 1, 0, 0, 1, 1, 0, 1) => {
-                Instruction::Jump(JumpInstruction::CallN16(self.nomnom(mem)))
+                Instruction::Jump(JumpInstruction::CallN16(Self::nomnom(&mut next)))
             }
             (1, 1, a, b, c, 1, 1, 1) => {
                 Instruction::Jump(JumpInstruction::Rst((a << 2 | b << 1 | c) as u16 * 8))
@@ -196,76 +270,77 @@ impl Disassembler {
                 Instruction::Stack(StackInstruction::PushR16(RegisterPairStk::from_bits(a, b)))
             }
 
-            (1, 1, 0, 0, 1, 0, 1, 1) => self.parse_prefix(mem),
+            (1, 1, 0, 0, 1, 0, 1, 1) => Self::parse_prefix(&mut next)?,
 
             (1, 1, 1, 0, 0, 0, 1, 0) => Instruction::Load(LoadInstruction::LdhMemCA),
             (1, 1, 1, 0, 0, 0, 0, 0) => {
-                Instruction::Load(LoadInstruction::LdhMemN8A(self.nom(mem)))
+                Instruction::Load(LoadInstruction::LdhMemN8A(next()))
             }
             (1, 1, 1, 0, 1, 0, 1, 0) => {
-                Instruction::Load(LoadInstruction::LdMemN16A(self.nomnom(mem)))
+                Instruction::Load(LoadInstruction::LdMemN16A(Self::nomnom(&mut next)))
             }
             (1, 1, 1, 1, 0, 0, 1, 0) => Instruction::Load(LoadInstruction::LdhAMemC),
             (1, 1, 1, 1, 0, 0, 0, 0) => {
-                Instruction::Load(LoadInstruction::LdhAMemN8(self.nom(mem)))
+                Instruction::Load(LoadInstruction::LdhAMemN8(next()))
             }
             (1, 1, 1, 1, 1, 0, 1, 0) => {
-                Instruction::Load(LoadInstruction::LdAMemN16(self.nomnom(mem)))
+                Instruction::Load(LoadInstruction::LdAMemN16(Self::nomnom(&mut next)))
             }
 
             (1, 1, 1, 0, 1, 0, 0, 0) => {
-                Instruction::Stack(StackInstruction::AddSPE8(self.nom(mem) as i8))
+                Instruction::Stack(StackInstruction::AddSPE8(next() as i8))
             }
             (1, 1, 1, 1, 1, 0, 0, 0) => {
-                Instruction::Stack(StackInstruction::LdHLSPPlusE8(self.nom(mem) as i8))
+                Instruction::Stack(StackInstruction::LdHLSPPlusE8(next() as i8))
             }
             (1, 1, 1, 1, 1, 0, 0, 1) => Instruction::Stack(StackInstruction::LdSPHL),
 
             (1, 1, 1, 1, 0, 0, 1, 1) => Instruction::Misc(MiscInstruction::Di),
             (1, 1, 1, 1, 1, 0, 1, 1) => Instruction::Misc(MiscInstruction::Ei),
 
-            _ => panic!("Invalid instruction: {:08b}", byte),
+            // only the undefined opcodes are left
+            _ => Instruction::Misc(MiscInstruction::Illegal(byte)),
         };
-        debug!("{:?}", instruction);
-        (instruction, self.cursor as u16)
+        Ok(instruction)
     }
 
-    fn parse_prefix<M>(&mut self, mem: &M) -> Instruction
+    fn parse_prefix<F>(next: &mut F) -> Result<Instruction, Error>
     where
-        M: Memory,
+        F: FnMut() -> u8,
     {
-        match Self::bits_tup(self.nom(mem)) {
+        let byte = next();
+        let instruction = match Self::bits_tup(byte) {
             (0, 0, 0, 0, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::RlcMemHL),
             (0, 0, 0, 0, 0, a, b, c) => {
-                Instruction::Bit(BitInstruction::Rlc(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Rlc(Register::from_bits(a, b, c)?))
             }
             (0, 0, 0, 0, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::RrcMemHL),
             (0, 0, 0, 0, 1, a, b, c) => {
-                Instruction::Bit(BitInstruction::Rrc(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Rrc(Register::from_bits(a, b, c)?))
             }
             (0, 0, 0, 1, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::RlMemHL),
             (0, 0, 0, 1, 0, a, b, c) => {
-                Instruction::Bit(BitInstruction::Rl(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Rl(Register::from_bits(a, b, c)?))
             }
             (0, 0, 0, 1, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::RrMemHL),
             (0, 0, 0, 1, 1, a, b, c) => {
-                Instruction::Bit(BitInstruction::Rr(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Rr(Register::from_bits(a, b, c)?))
             }
             (0, 0, 1, 0, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::SlaMemHL),
             (0, 0, 1, 0, 0, a, b, c) => {
-                Instruction::Bit(BitInstruction::Sla(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Sla(Register::from_bits(a, b, c)?))
             }
             (0, 0, 1, 0, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::SraMemHL),
             (0, 0, 1, 0, 1, a, b, c) => {
-                Instruction::Bit(BitInstruction::Sra(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Sra(Register::from_bits(a, b, c)?))
             }
             (0, 0, 1, 1, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::SwapMemHL),
             (0, 0, 1, 1, 0, a, b, c) => {
-                Instruction::Bit(BitInstruction::Swap(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Swap(Register::from_bits(a, b, c)?))
             }
             (0, 0, 1, 1, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::SrlMemHL),
             (0, 0, 1, 1, 1, a, b, c) => {
-                Instruction::Bit(BitInstruction::Srl(Register::from_bits(a, b, c)))
+                Instruction::Bit(BitInstruction::Srl(Register::from_bits(a, b, c)?))
             }
 
             (0, 1, x, y, z, 1, 1, 0) => {
@@ -273,24 +348,25 @@ impl Disassembler {
             }
             (0, 1, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Bit(
                 x << 2 | y << 1 | z,
-                Register::from_bits(a, b, c),
+                Register::from_bits(a, b, c)?,
             )),
             (1, 0, x, y, z, 1, 1, 0) => {
                 Instruction::Bit(BitInstruction::ResMemHL(x << 2 | y << 1 | z))
             }
             (1, 0, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Res(
                 x << 2 | y << 1 | z,
-                Register::from_bits(a, b, c),
+                Register::from_bits(a, b, c)?,
             )),
             (1, 1, x, y, z, 1, 1, 0) => {
                 Instruction::Bit(BitInstruction::SetMemHL(x << 2 | y << 1 | z))
             }
             (1, 1, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Set(
                 x << 2 | y << 1 | z,
-                Register::from_bits(a, b, c),
+                Register::from_bits(a, b, c)?,
             )),
-            x => panic!("Invalid prefix instruction: {:?}", x),
-        }
+            _ => return Err(Error::InvalidOpcode(byte)),
+        };
+        Ok(instruction)
     }
 
     pub const fn bits_tup(byte: u8) -> (u8, u8, u8, u8, u8, u8, u8, u8) {
@@ -310,22 +386,13 @@ impl Disassembler {
         ((high as u16) << 8) | low as u16
     }
 
-    fn nom<M>(&mut self, memory: &M) -> u8
-    where
-        M: Memory,
-    {
-        self.cursor += 1;
-        memory.get((self.cursor - 1) as u16)
-    }
-
-    fn nomnom<M>(&mut self, memory: &M) -> u16
+    // little endian, the low byte comes first
+    fn nomnom<F>(next: &mut F) -> u16
     where
-        M: Memory,
+        F: FnMut() -> u8,
     {
-        self.cursor += 2;
-        Self::u16_from_bytes(
-            memory.get((self.cursor - 1) as u16),
-            memory.get((self.cursor - 2) as u16),
-        )
+        let low = next();
+        let high = next();
+        Self::u16_from_bytes(high, low)
     }
 }
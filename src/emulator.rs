@@ -0,0 +1,158 @@
+use crate::apu::{Apu, Oscilloscope};
+use crate::cpu::Cpu;
+use crate::error::Error;
+use crate::frame::triple_buffer;
+use crate::joypad::Buttons;
+use crate::memory::{MappedMemory, Mbc, Memory, RomOnlyMbc};
+use crate::ppu::Ppu;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timer::Timer;
+use crate::{CartridgeHeader, CartridgeType, ControlMsg};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+
+// machine cycles from one VBlank to the next
+pub const CYCLES_PER_FRAME: usize = 154 * 456 / 4;
+
+// the cartridges the facade can run, so that the controller type stays out of its API
+enum Cartridge {
+    RomOnly(RomOnlyMbc),
+}
+
+impl Mbc for Cartridge {
+    // Emulator::new picks the controller from the header
+    fn new(rom: Vec<u8>) -> Self {
+        Cartridge::RomOnly(RomOnlyMbc::new(rom))
+    }
+
+    fn rom(&self) -> &[u8] {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.rom(),
+        }
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.read_rom(addr),
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.read_ram(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.write(addr, value),
+        }
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.rom_bank(addr),
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.save_state(state),
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        match self {
+            Cartridge::RomOnly(mbc) => mbc.load_state(state),
+        }
+    }
+}
+
+// the whole system without threads or real time pacing, for embedding it in tools and tests
+pub struct Emulator {
+    cpu: Cpu<MappedMemory<Cartridge>>,
+    _control: Sender<ControlMsg>, // keeps the channel the CPU expects open
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u8>) -> Result<Self, Error> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let mbc = match header.cartridge_type {
+            CartridgeType::RomOnly => Cartridge::RomOnly(RomOnlyMbc::new(rom)),
+            type_ => return Err(Error::UnsupportedCartridge(type_)),
+        };
+
+        // nobody reads the frames, framebuffer() looks at the PPU's own
+        let ppu = Ppu::new(triple_buffer().0, triple_buffer().0);
+        let apu = Apu::new(Arc::new(Mutex::new(Oscilloscope::default())));
+        let mut mem = MappedMemory::new(mbc, ppu, Timer::new(), apu);
+        mem.ppu.show_vram = false;
+
+        let (control, recv) = mpsc::channel();
+        Ok(Self {
+            cpu: Cpu::new(mem, recv),
+            _control: control,
+        })
+    }
+
+    // executes one instruction and returns the machine cycles it took
+    pub fn step_instruction(&mut self) -> usize {
+        self.cpu.step()
+    }
+
+    // runs until the next VBlank, or for the length of a frame while the LCD is off, and returns
+    // the machine cycles that took
+    pub fn step_frame(&mut self) -> usize {
+        let frames = self.cpu.mem.ppu.frames();
+        let mut cycles = 0;
+        while self.cpu.mem.ppu.frames() == frames && cycles < CYCLES_PER_FRAME {
+            cycles += self.cpu.step();
+        }
+        cycles
+    }
+
+    // runs whole instructions until at least the given machine cycles have passed and returns how
+    // many did
+    pub fn run_for_cycles(&mut self, cycles: usize) -> usize {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            elapsed += self.cpu.step();
+        }
+        elapsed
+    }
+
+    // 160x144 RGBA pixels
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.mem.ppu.framebuffer()
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.cpu.mem.joypad.set_buttons(buttons);
+    }
+
+    // keeps the audio output for audio_samples, which then has to be called regularly
+    pub fn enable_audio(&mut self) {
+        self.cpu.mem.apu.collect_samples = true;
+    }
+
+    // (left, right) samples at apu::SAMPLE_RATE produced since the last call, empty unless
+    // enable_audio was called
+    pub fn audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.cpu.mem.apu.take_samples()
+    }
+
+    // a snapshot of the whole system, see savestate for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    // fails without changing the machine if the state is corrupted or was made with another ROM
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.cpu.load_state(data)
+    }
+
+    // the byte the CPU would read at addr
+    pub fn read(&self, addr: u16) -> u8 {
+        self.cpu.mem.get(addr)
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{CartridgeType, ControlMsg};
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    // reading the ROM, reading and writing save state files
+    Io(String),
+
+    // loading a cartridge
+    RomTooSmall(usize),
+    InvalidCartridgeType(u8),
+    UnsupportedCartridge(CartridgeType),
+
+    // decoding, the values can't come from a valid opcode
+    InvalidOpcode(u8),
+    InvalidRegisterBits(u8),
+    InvalidInterrupt(u8),
+
+    // the CPU ran into one of the undefined opcodes and locked up like the hardware does
+    IllegalOpcode(u8),
+
+    UnhandledControlMsg(ControlMsg),
+
+    // loading a save state
+    InvalidSaveState(String),
+    SaveStateTooNew(u16),
+    SaveStateForOtherRom,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(message) => write!(f, "I/O error: {}", message),
+            Error::RomTooSmall(len) => {
+                write!(f, "ROM is {} bytes long, too short for a header", len)
+            }
+            Error::InvalidCartridgeType(value) => {
+                write!(f, "invalid cartridge type {:#04X}", value)
+            }
+            Error::UnsupportedCartridge(type_) => {
+                write!(f, "unsupported cartridge type {:?}", type_)
+            }
+            Error::InvalidOpcode(opcode) => write!(f, "invalid opcode {:#04X}", opcode),
+            Error::InvalidRegisterBits(bits) => write!(f, "invalid register bits {:03b}", bits),
+            Error::InvalidInterrupt(value) => write!(f, "invalid interrupt value {:#04X}", value),
+            Error::IllegalOpcode(opcode) => write!(f, "illegal opcode {:#04X}", opcode),
+            Error::UnhandledControlMsg(msg) => write!(f, "unhandled control message {:?}", msg),
+            Error::InvalidSaveState(reason) => write!(f, "invalid save state: {}", reason),
+            Error::SaveStateTooNew(version) => {
+                write!(f, "save state format {} is too new to load", version)
+            }
+            Error::SaveStateForOtherRom => write!(f, "save state was made with a different ROM"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error.to_string())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::ppu::Rgba;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub const FRAME_LEN: usize = 160 * 144;
+
+const INDEX: u8 = 0b011;
+const FRESH: u8 = 0b100; // the middle buffer holds a frame the reader hasn't seen
+
+// three frames shared by one writer and one reader. Each side owns one buffer and swaps it with
+// the middle one, so finished frames change hands without locks or allocations
+struct Shared {
+    buffers: [UnsafeCell<Vec<Rgba>>; 3],
+    middle: AtomicU8,
+    published: AtomicU64,
+}
+
+// a buffer is only touched by the side whose index points at it, and indices only change hands
+// through the atomic swaps on `middle`
+unsafe impl Sync for Shared {}
+
+pub struct FrameWriter {
+    shared: Arc<Shared>,
+    back: u8,
+}
+
+pub struct FrameReader {
+    shared: Arc<Shared>,
+    front: u8,
+}
+
+pub fn triple_buffer() -> (FrameWriter, FrameReader) {
+    let buffer = || UnsafeCell::new(vec![Rgba::BLACK; FRAME_LEN]);
+    let shared = Arc::new(Shared {
+        buffers: [buffer(), buffer(), buffer()],
+        middle: AtomicU8::new(1),
+        published: AtomicU64::new(0),
+    });
+    let writer = FrameWriter {
+        shared: shared.clone(),
+        back: 0,
+    };
+    let reader = FrameReader { shared, front: 2 };
+    (writer, reader)
+}
+
+impl FrameWriter {
+    // the frame being drawn
+    pub fn buffer(&mut self) -> &mut [Rgba] {
+        unsafe { &mut *self.shared.buffers[self.back as usize].get() }
+    }
+
+    // hands the frame being drawn to the reader, replacing one it hasn't picked up yet
+    pub fn publish(&mut self) {
+        let previous = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX;
+        self.shared.published.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl FrameReader {
+    // the newest frame if one was published since the last call
+    pub fn latest(&mut self) -> Option<&[Rgba]> {
+        if self.shared.middle.load(Ordering::Acquire) & FRESH == 0 {
+            return None;
+        }
+        let previous = self.shared.middle.swap(self.front, Ordering::AcqRel);
+        self.front = previous & INDEX;
+        Some(self.current())
+    }
+
+    // the frame picked up last
+    pub fn current(&self) -> &[Rgba] {
+        unsafe { &*self.shared.buffers[self.front as usize].get() }
+    }
+
+    // frames published since the start, including ones that were replaced before being read
+    pub fn published(&self) -> u64 {
+        self.shared.published.load(Ordering::Relaxed)
+    }
+}
@@ -37,7 +37,7 @@ DEC r16
 INC r16
 */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum ArithmeticInstruction {
     AdcAR8(Register),       // Add with carry, from register to A
     AdcAMemHL,              // Add with carry, from memory at HL to A
@@ -103,7 +103,7 @@ SRL r8
 SRL [HL]
 */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum BitInstruction {
     Bit(u8, Register), // Test u'th bit in register, set zero flag if not set
     BitMemHL(u8),      // Test u'th bit in memory at HL, set zero flag if not set
@@ -155,7 +155,7 @@ LD A,[HLI]
 LD A,[HLD]
 */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum LoadInstruction {
     LdR8R8(Register, Register), // Load (copy) value in register on the right into register on the left.
     LdR8N8(Register, u8),       // Load immediate value into register.
@@ -194,7 +194,7 @@ RETI
 RST vec
 */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum Condition {
     NotZero,  // Z flag is not set.
     Zero,     // Z flag is set.
@@ -213,6 +213,7 @@ impl Condition {
     }
 }
 
+#[derive(Copy, Clone)]
 pub enum JumpInstruction {
     CallN16(u16),              // Call subroutine at immediate value.
     CallCCN16(Condition, u16), // Call subroutine at immediate value if condition is met.
@@ -262,7 +263,7 @@ PUSH AF
 PUSH r16
 */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum StackInstruction {
     AddHLSP,                  // Add SP to HL.  TODO: why are there unused variants?
     AddSPE8(i8),              // Add immediate value to SP.
@@ -291,7 +292,7 @@ SCF
 STOP
  */
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum MiscInstruction {
     Ccf,  // Complement carry flag.
     Cpl,  // Complement A.
@@ -302,9 +303,10 @@ pub enum MiscInstruction {
     Nop,  // No operation.
     Scf,  // Set carry flag.
     Stop, // Stop CPU.
+    Illegal(u8), // Undefined opcode, locks up the CPU.
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum Instruction {
     Arithmetic(ArithmeticInstruction),
     Bit(BitInstruction),
@@ -313,3 +315,54 @@ pub enum Instruction {
     Stack(StackInstruction),
     Misc(MiscInstruction),
 }
+
+impl Instruction {
+    // fills in the immediate operand of an instruction template from the decode table,
+    // 8 bit immediates are taken from the low byte
+    pub fn with_immediate(self, imm: u16) -> Instruction {
+        let n8 = imm as u8;
+        let e8 = imm as u8 as i8;
+        match self {
+            Instruction::Arithmetic(x) => Instruction::Arithmetic(match x {
+                ArithmeticInstruction::AdcAN8(_) => ArithmeticInstruction::AdcAN8(n8),
+                ArithmeticInstruction::AddAN8(_) => ArithmeticInstruction::AddAN8(n8),
+                ArithmeticInstruction::AndAN8(_) => ArithmeticInstruction::AndAN8(n8),
+                ArithmeticInstruction::CpAN8(_) => ArithmeticInstruction::CpAN8(n8),
+                ArithmeticInstruction::OrAN8(_) => ArithmeticInstruction::OrAN8(n8),
+                ArithmeticInstruction::SbcAN8(_) => ArithmeticInstruction::SbcAN8(n8),
+                ArithmeticInstruction::SubAN8(_) => ArithmeticInstruction::SubAN8(n8),
+                ArithmeticInstruction::XorAN8(_) => ArithmeticInstruction::XorAN8(n8),
+                x => x,
+            }),
+            Instruction::Load(x) => Instruction::Load(match x {
+                LoadInstruction::LdR8N8(reg, _) => LoadInstruction::LdR8N8(reg, n8),
+                LoadInstruction::LdR16N16(reg, _) => LoadInstruction::LdR16N16(reg, imm),
+                LoadInstruction::LdMemHLN8(_) => LoadInstruction::LdMemHLN8(n8),
+                LoadInstruction::LdMemN16A(_) => LoadInstruction::LdMemN16A(imm),
+                LoadInstruction::LdhMemN16A(_) => LoadInstruction::LdhMemN16A(imm),
+                LoadInstruction::LdAMemN16(_) => LoadInstruction::LdAMemN16(imm),
+                LoadInstruction::LdhAMemN16(_) => LoadInstruction::LdhAMemN16(imm),
+                LoadInstruction::LdhAMemN8(_) => LoadInstruction::LdhAMemN8(n8),
+                LoadInstruction::LdhMemN8A(_) => LoadInstruction::LdhMemN8A(n8),
+                x => x,
+            }),
+            Instruction::Jump(x) => Instruction::Jump(match x {
+                JumpInstruction::CallN16(_) => JumpInstruction::CallN16(imm),
+                JumpInstruction::CallCCN16(c, _) => JumpInstruction::CallCCN16(c, imm),
+                JumpInstruction::JpN16(_) => JumpInstruction::JpN16(imm),
+                JumpInstruction::JpCCN16(c, _) => JumpInstruction::JpCCN16(c, imm),
+                JumpInstruction::JrN8(_) => JumpInstruction::JrN8(e8),
+                JumpInstruction::JrCCN8(c, _) => JumpInstruction::JrCCN8(c, e8),
+                x => x,
+            }),
+            Instruction::Stack(x) => Instruction::Stack(match x {
+                StackInstruction::AddSPE8(_) => StackInstruction::AddSPE8(e8),
+                StackInstruction::LdSPN16(_) => StackInstruction::LdSPN16(imm),
+                StackInstruction::LdMemN16SP(_) => StackInstruction::LdMemN16SP(imm),
+                StackInstruction::LdHLSPPlusE8(_) => StackInstruction::LdHLSPPlusE8(e8),
+                x => x,
+            }),
+            x => x,
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::error::Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+use bitflags::bitflags;
 use log::debug;
 
 pub struct Joypad {
@@ -19,6 +22,21 @@ pub enum JoypadKey {
     Start,
 }
 
+bitflags! {
+    // the buttons held down, for setting them all at once
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const RIGHT = 1 << 0;
+        const LEFT = 1 << 1;
+        const UP = 1 << 2;
+        const DOWN = 1 << 3;
+        const A = 1 << 4;
+        const B = 1 << 5;
+        const SELECT = 1 << 6;
+        const START = 1 << 7;
+    }
+}
+
 impl Default for Joypad {
     fn default() -> Self {
         Self::new()
@@ -36,13 +54,8 @@ impl Joypad {
     }
 
     pub fn read(&self) -> u8 {
-        let out = if self.data & 0x20 == 0 {
-            self.buttons
-        } else if self.data & 0x10 == 0 {
-            self.dpad
-        } else {
-            0xFF
-        };
+        // the low nibble already holds the buttons of the selected groups, see update
+        let out = 0xC0 | (self.data & 0x3F);
         debug!("Joypad read out: {:#X}", out);
         out
     }
@@ -53,6 +66,11 @@ impl Joypad {
         self.update();
     }
 
+    // true if a button in one of the selected groups is held down
+    pub fn any_pressed(&self) -> bool {
+        self.data & 0x0F != 0x0F
+    }
+
     fn update(&mut self) {
         let old_data = self.data & 0xF;
         let mut new_data = 0xF;
@@ -70,6 +88,12 @@ impl Joypad {
         self.data = (self.data & 0xF0) | new_data;
     }
 
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.dpad = !buttons.bits() & 0x0F;
+        self.buttons = !(buttons.bits() >> 4) & 0x0F;
+        self.update();
+    }
+
     pub fn keydown(&mut self, key: JoypadKey) {
         match key {
             JoypadKey::Right => self.dpad &= !(1 << 0),
@@ -98,3 +122,23 @@ impl Joypad {
         self.update();
     }
 }
+
+// only the selected groups are restored, the buttons stay as they are held right now
+impl Snapshot for Joypad {
+    fn save(&self, state: &mut StateWriter) {
+        state.section(b"JOYP");
+        state.u8(self.data);
+        state.u8(self.interrupt);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.section(b"JOYP")?;
+        let data = state.u8()?;
+        self.interrupt = state.u8()?;
+        self.data = (data & 0xF0) | 0x0F;
+        let interrupt = self.interrupt;
+        self.update();
+        self.interrupt = interrupt;
+        Ok(())
+    }
+}
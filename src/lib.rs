@@ -1,18 +1,35 @@
+use crate::error::Error;
 use bitflags::bitflags;
-use eframe::egui::Color32;
 
-mod apu;
+// puffin scopes, compiled out without the profiling feature
+macro_rules! profile_function {
+    () => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_function!();
+    };
+}
+
+pub mod apu;
 mod arithmetic;
+pub mod block_cache;
 pub mod cpu;
 pub mod disassembler;
+mod emulator;
+pub mod error;
+pub mod frame;
 pub mod isa;
 pub mod joypad;
 pub mod memory;
 pub mod ppu;
+pub mod rewind;
+pub mod savestate;
 mod serial;
 pub mod timer;
+#[cfg(feature = "gui")]
 pub mod ui;
 
+pub use emulator::{Emulator, CYCLES_PER_FRAME};
+
 bitflags! {
     struct Flags: u8 {
         const CARRY = 1 << 4;
@@ -96,20 +113,28 @@ pub enum Register {
 }
 
 impl Register {
-    pub fn from_bits(a: u8, b: u8, c: u8) -> Register {
+    // 0b110 encodes [HL] and has to be handled by the caller
+    pub fn from_bits(a: u8, b: u8, c: u8) -> Result<Register, Error> {
         match (a, b, c) {
-            (0, 0, 0) => Register::B,
-            (0, 0, 1) => Register::C,
-            (0, 1, 0) => Register::D,
-            (0, 1, 1) => Register::E,
-            (1, 0, 0) => Register::H,
-            (1, 0, 1) => Register::L,
-            (1, 1, 1) => Register::A,
-            _ => panic!("Invalid register bits {a}{b}{c}"),
+            (0, 0, 0) => Ok(Register::B),
+            (0, 0, 1) => Ok(Register::C),
+            (0, 1, 0) => Ok(Register::D),
+            (0, 1, 1) => Ok(Register::E),
+            (1, 0, 0) => Ok(Register::H),
+            (1, 0, 1) => Ok(Register::L),
+            (1, 1, 1) => Ok(Register::A),
+            _ => Err(Error::InvalidRegisterBits(a << 2 | b << 1 | c)),
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum CartridgeType {
     RomOnly,
@@ -142,9 +167,11 @@ pub enum CartridgeType {
     HuC1RamBattery,
 }
 
-impl From<u8> for CartridgeType {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for CartridgeType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        let type_ = match value {
             0x00 => CartridgeType::RomOnly,
             0x01 => CartridgeType::Mbc1,
             0x02 => CartridgeType::Mbc1Ram,
@@ -173,13 +200,30 @@ impl From<u8> for CartridgeType {
             0xFD => CartridgeType::BandaiTama5,
             0xFE => CartridgeType::HuC3,
             0xFF => CartridgeType::HuC1RamBattery,
-            _ => panic!("Invalid cartridge type {value}"),
-        }
+            _ => return Err(Error::InvalidCartridgeType(value)),
+        };
+        Ok(type_)
     }
 }
 
-pub struct FrameData {
-    pub framebuffer: Vec<Color32>,
+// the parts of the cartridge header at 0x0100-0x014F the emulator uses
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cartridge_type: CartridgeType,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, Error> {
+        if rom.len() < 0x150 {
+            return Err(Error::RomTooSmall(rom.len()));
+        }
+        let title = rom[0x134..0x143].iter().map(|&c| c as char).collect();
+        let cartridge_type = CartridgeType::try_from(rom[0x147])?;
+        Ok(Self {
+            title,
+            cartridge_type,
+        })
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -187,7 +231,16 @@ pub enum ControlMsg {
     Terminate,
     Debug,
     ShowVRam(bool),
+    BlankWhenLcdOff(bool),
     KeyDown(joypad::JoypadKey),
     KeyUp(joypad::JoypadKey),
+    MuteChannel(apu::AudioChannel, bool),
+    SoloChannel(apu::AudioChannel, bool),
     Reset,
+    SetEngine(cpu::Engine),
+    BreakOnIllegal(bool),
+    Continue,
+    SaveState(u8), // slot number
+    LoadState(u8),
+    Rewind(bool), // held down
 }
@@ -1,20 +1,25 @@
-use eframe::egui::{Color32, Context, TextureOptions};
+use eframe::egui::{Context, TextureOptions};
 use eframe::epaint::TextureHandle;
 use eframe::{egui, Frame};
 use log::info;
+use rustgb::apu::{Apu, Oscilloscope};
 use rustgb::cpu::Cpu;
 use rustgb::joypad::JoypadKey;
 use rustgb::memory::{MappedMemory, Mbc, RomOnlyMbc};
+use rustgb::frame::triple_buffer;
 use rustgb::ppu::Ppu;
+use rustgb::rewind::RewindConfig;
 use rustgb::timer::Timer;
 use rustgb::ui::{App, FrameHistory};
-use rustgb::{CartridgeType, ControlMsg, FrameData};
+use rustgb::error::Error;
+use rustgb::{CartridgeHeader, CartridgeType, ControlMsg};
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Mutex};
 use std::{fs, thread};
 
-pub fn main() {
+pub fn main() -> Result<(), Error> {
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
         .filter(Some("rustgb::cpu"), log::LevelFilter::Info)
@@ -26,54 +31,52 @@ pub fn main() {
         // .filter(Some("rustgb::ppu"), log::LevelFilter::Info)
         .init();
 
-    let server_addr = "127.0.0.1:8585";
-    let _server = puffin_http::Server::new(server_addr).unwrap();
+    #[cfg(feature = "profiling")]
+    let _server = puffin_http::Server::new("127.0.0.1:8585").unwrap();
 
     // let boot_rom = fs::read("boot.gb").expect("Unable to read boot rom");
     // let boot_rom = fs::read("gb-test-roms-master/cpu_instrs/individual/04-op r,imm.gb").expect("Unable to read boot rom");
 
-    let rom = fs::read("roms/tetris.gb").expect("Unable to read rom");
+    let rom_path = PathBuf::from("roms/tetris.gb");
+    let rom = fs::read(&rom_path)?;
     // let rom = fs::read("gb-test-roms-master/cpu_instrs/individual/01-special.gb").expect("Unable to read rom");
 
-    let title = rom[0x134..0x143]
-        .iter()
-        .map(|&c| c as char)
-        .collect::<String>();
+    let header = CartridgeHeader::parse(&rom)?;
+    let title = header.title;
     info!("Loading {title}...");
 
-    let mbc = rom[0x147];
-    let type_ = CartridgeType::from(mbc);
+    let type_ = header.cartridge_type;
     let mbc = match type_ {
         CartridgeType::RomOnly => RomOnlyMbc::new(rom),
-        _ => panic!("Unsupported cartridge type {type_:?}"),
+        _ => return Err(Error::UnsupportedCartridge(type_)),
     };
     info!("Memory Bank Controller: {type_:?}");
 
-    let (send_from_cpu, recv_from_cpu) = mpsc::channel::<FrameData>();
     let (send_to_cpu, recv_to_cpu) = mpsc::channel::<ControlMsg>();
-    
-    
-    let framebuffer = Arc::new(Mutex::new(vec![Color32::BLACK; 160 * 144]));
-    let framebuffer_dirty = Arc::new(Mutex::new(false));
-    let debug_framebuffer = Arc::new(Mutex::new(vec![Color32::BLACK; 160 * 144]));
-    let debug_framebuffer_dirty = Arc::new(Mutex::new(false));
-    
-    let ppu = Ppu::new(framebuffer.clone(), debug_framebuffer.clone(), framebuffer_dirty.clone(), debug_framebuffer_dirty.clone());
+
+    let (frames, frame_reader) = triple_buffer();
+    let (debug_frames, debug_frame_reader) = triple_buffer();
+    let oscilloscope = Arc::new(Mutex::new(Oscilloscope::default()));
+
+    let ppu = Ppu::new(frames, debug_frames);
     let timer = Timer::new();
-    let mmu = MappedMemory::new(mbc, ppu, timer);
+    let apu = Apu::new(oscilloscope.clone());
+    let mmu = MappedMemory::new(mbc, ppu, timer, apu);
     let mut cpu = Cpu::new(mmu, recv_to_cpu);
+    cpu.rom_path = Some(rom_path);
+    cpu.set_rewind(Some(RewindConfig::default()));
+    let fault = cpu.fault_status.clone();
     let cpu_handle = thread::spawn(move || cpu.run());
 
     let app = App::new(
-        recv_from_cpu,
         send_to_cpu.clone(),
-        framebuffer.clone(),
-        debug_framebuffer.clone(),
-        framebuffer_dirty.clone(),
-        debug_framebuffer_dirty.clone(),
+        frame_reader,
+        debug_frame_reader,
+        oscilloscope.clone(),
+        fault,
     );
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([512.0, 780.0]),
+        viewport: egui::ViewportBuilder::default().with_inner_size([640.0, 900.0]),
         vsync: true,
         ..Default::default()
     };
@@ -88,4 +91,5 @@ pub fn main() {
     .unwrap();
     send_to_cpu.send(ControlMsg::Terminate).unwrap();
     cpu_handle.join().unwrap();
+    Ok(())
 }
@@ -1,9 +1,13 @@
+use crate::apu::Apu;
+use crate::error::Error;
 use crate::joypad::Joypad;
 use crate::ppu::Ppu;
+use crate::savestate::{rom_checksum, Snapshot, StateReader, StateWriter};
 use crate::serial::Serial;
 use crate::timer::Timer;
-use crate::{ControlMsg, Flags};
+use crate::{ControlMsg, Flags, Model};
 use log::{debug, info, warn};
+use std::cell::RefCell;
 
 #[derive(Default, Copy, Clone, Debug)]
 pub struct RegisterPairValue {
@@ -55,9 +59,27 @@ impl From<u16> for RegisterPairValue {
 
 pub trait Mbc {
     fn new(rom: Vec<u8>) -> Self;
+    fn rom(&self) -> &[u8];
     fn read_rom(&self, addr: u16) -> u8;
     fn read_ram(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, value: u8);
+
+    // the ROM bank mapped at addr
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 {
+            0
+        } else {
+            1
+        }
+    }
+
+    // banks, cartridge RAM and clocks for save states, controllers without any have nothing to
+    // save
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 pub struct RomOnlyMbc {
@@ -67,8 +89,12 @@ impl Mbc for RomOnlyMbc {
     fn new(rom: Vec<u8>) -> Self {
         Self { rom }
     }
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
     fn read_rom(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        // ROMs smaller than 32 KiB leave the rest of the area unconnected
+        self.rom.get(addr as usize).copied().unwrap_or(0xff)
     }
     fn read_ram(&self, addr: u16) -> u8 {
         warn!(
@@ -98,6 +124,10 @@ impl Mbc for Mbc1 {
         todo!()
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     fn read_rom(&self, addr: u16) -> u8 {
         todo!()
     }
@@ -114,6 +144,14 @@ impl Mbc for Mbc1 {
         }
     }
 
+    fn rom_bank(&self, addr: u16) -> usize {
+        match (addr, self.banking_mode) {
+            (0x0000..=0x3FFF, false) => 0,
+            (0x0000..=0x3FFF, true) => self.ram_bank << 5,
+            _ => self.ram_bank << 5 | self.rom_bank.max(1),
+        }
+    }
+
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
             0x0000..=0x1fff => {
@@ -124,7 +162,7 @@ impl Mbc for Mbc1 {
             }
             0x4000..=0x5fff => {
                 if self.num_rombanks > 0x20 {
-                    panic!("Only at most 0x20 rom banks is supported");
+                    warn!("[Mbc1] Only at most 0x20 rom banks are supported");
                 }
                 self.ram_bank = (value & 0x03) as usize;
             }
@@ -157,46 +195,126 @@ pub trait Memory {
 
     fn clear_requested_interrupt(&mut self, interrupt: Interrupt);
 
-    fn control_msg(&mut self, msg: ControlMsg) {
-        panic!("This memory implementation does not support control messages.")
+    // true if a joypad button in a selected group is held, which wakes the CPU from STOP
+    fn button_pressed(&self) -> bool {
+        false
+    }
+
+    // called when the CPU enters STOP. Resets DIV and performs a pending CGB speed switch,
+    // returns true if the speed was switched
+    fn stop(&mut self) -> bool {
+        false
+    }
+
+    // the cartridge ROM bank mapped at addr, code caches are keyed by it
+    fn rom_bank(&self, _addr: u16) -> usize {
+        0
+    }
+
+    // the CPU put addr on the bus for a 16-bit increment or decrement without accessing it
+    fn inc_dec_bus(&mut self, _addr: u16) {}
+
+    // true while a DMA transfer owns the bus, reads can see other values than get would return
+    // without it
+    fn dma_active(&self) -> bool {
+        false
+    }
+
+    // true from a write to the DMA register until its transfer takes over the bus, which can
+    // happen in the middle of the next instruction
+    fn dma_starting(&self) -> bool {
+        false
+    }
+
+    fn control_msg(&mut self, msg: ControlMsg) -> Result<(), Error> {
+        Err(Error::UnhandledControlMsg(msg))
+    }
+
+    // everything behind the bus for save states, see savestate. A failed load can leave some
+    // sections loaded, Cpu::load_state puts them back
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// OAM DMA copying one byte per machine cycle from source to OAM
+struct OamDma {
+    source: u16,
+    index: u16,
+}
+
+impl OamDma {
+    // the address the transfer reads from in the current machine cycle
+    fn addr(&self) -> u16 {
+        self.source + self.index
     }
 }
 
 pub struct MappedMemory<MBC: Mbc> {
     mbc: MBC,
-    work_ram: [u8; 0x2000],
+    work_ram: [u8; 0x8000],
     high_ram: [u8; 0x7F],
-    wram_bank: u8, // 1-7
-    joypad: Joypad,
+    wram_bank: u8, // 1-7, switchable through SVBK on CGB
+    vram_bank: u8,
+    dma_source: u8, // last value written to the DMA register
+    dma: Option<OamDma>,
+    dma_start: Option<(u8, u8)>, // source page and machine cycles until it takes over the bus
+    bg_palette_index: u8,
+    obj_palette_index: u8,
+    bg_palette_ram: [u8; 0x40],
+    obj_palette_ram: [u8; 0x40],
+    pub joypad: Joypad,
     pub ppu: Ppu,
     pub timer: Timer,
+    pub apu: Apu,
     serial: Serial,
     int_enable: u8,
     int_request: u8,
+    pub model: Model,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    apu_divider: bool, // the APU keeps running at normal speed in double speed mode
+    rom_checksum: u32, // save states only load into the ROM they were made with
 }
 
 impl<MBC> MappedMemory<MBC>
 where
     MBC: Mbc,
 {
-    pub fn new(mbc: MBC, ppu: Ppu, timer: Timer) -> Self {
+    pub fn new(mbc: MBC, ppu: Ppu, timer: Timer, apu: Apu) -> Self {
         let mut mmu = Self {
+            rom_checksum: rom_checksum(mbc.rom()),
             mbc,
-            work_ram: [0; 0x2000],
+            work_ram: [0; 0x8000],
             high_ram: [0; 0x7F],
             wram_bank: 1,
+            vram_bank: 0,
+            dma_source: 0xFF,
+            dma: None,
+            dma_start: None,
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+            bg_palette_ram: [0xFF; 0x40],
+            obj_palette_ram: [0xFF; 0x40],
             joypad: Joypad::new(),
             ppu,
             timer,
+            apu,
             serial: Serial::default(),
             int_enable: 0,
             int_request: 0,
+            model: Model::Dmg,
+            double_speed: false,
+            speed_switch_armed: false,
+            apu_divider: false,
         };
 
         mmu.write(0xFF00, 0xCF); // P1
         mmu.write(0xFF01, 0x00); // SB
         mmu.write(0xFF02, 0x7E); // SC
-        mmu.write(0xFF04, 0xAB); // DIV
+        // DIV is not written here, any write resets the system counter set up by Timer::new
         mmu.write(0xFF05, 0x00); // TIMA
         mmu.write(0xFF06, 0x00); // TMA
         mmu.write(0xFF07, 0xF8); // TAC
@@ -223,7 +341,7 @@ where
         mmu.write(0xFF25, 0xF3); // NR51
         mmu.write(0xFF26, 0xF1); // NR52
         mmu.write(0xFF40, 0x91); // LCDC
-        mmu.write(0xFF41, 0x85); // STAT
+        mmu.ppu.write(0xFF41, 0x85); // STAT, skipping the DMG write quirk
         mmu.write(0xFF42, 0x00); // SCY
         mmu.write(0xFF43, 0x00); // SCX
         mmu.write(0xFF45, 0x00); // LYC
@@ -236,73 +354,161 @@ where
         mmu
     }
 
+    // the unusable area between OAM and IO. DMG reads zeros, CGB repeats the high nibble of the
+    // lower address byte
+    fn prohibited_read(&self, addr: u16) -> u8 {
+        match self.model {
+            Model::Dmg if !self.ppu.oam_accessible() => 0xFF,
+            Model::Dmg => 0x00,
+            Model::Cgb => (addr as u8 & 0xF0) | (addr as u8 >> 4),
+        }
+    }
+
+    // CGB palette data registers, the index auto-increments after writes if bit 7 is set
+    fn write_palette(index: &mut u8, ram: &mut [u8; 0x40], value: u8) {
+        ram[(*index & 0x3F) as usize] = value;
+        if *index & 0x80 != 0 {
+            *index = 0x80 | (*index + 1) & 0x3F;
+        }
+    }
+
+    // the transfer starts after one more machine cycle, a running one keeps going until then
     fn dma_transfer(&mut self, value: u8) {
-        assert!(value <= 0xDF);
-        let start = (value as u16) << 8;
-        for i in 0..0xa0 {
-            let copied = self.get(start + i as u16);
-            self.ppu.oam[i] = copied;
+        self.dma_source = value;
+        self.dma_start = Some((value, 2));
+    }
+
+    // called once every machine cycle, after the CPU's bus access
+    fn dma_cycle(&mut self) {
+        if let Some(dma) = &mut self.dma {
+            let addr = dma.addr();
+            let index = dma.index as usize;
+            dma.index += 1;
+            if dma.index == 0xA0 {
+                self.dma = None;
+            }
+            self.ppu.oam[index] = self.read(addr);
+        }
+        if let Some((page, delay)) = &mut self.dma_start {
+            *delay -= 1;
+            if *delay == 0 {
+                // the DMA reads 0xE000-0xFFFF through the external bus, where it mirrors WRAM
+                let page = if *page >= 0xE0 { *page - 0x20 } else { *page };
+                self.dma = Some(OamDma {
+                    source: (page as u16) << 8,
+                    index: 0,
+                });
+                self.dma_start = None;
+            }
         }
     }
-}
 
-impl<MBC> Memory for MappedMemory<MBC>
-where
-    MBC: Mbc,
-{
-    fn get(&self, addr: u16) -> u8 {
-        // debug!("First tile: {:02X?}", &self.mem[0x8000..0x8016]);
-        let addr = if addr > 0xE000 && addr < 0xFE00 {
+    // the memory map as seen without any DMA transfer going on
+    fn read(&self, addr: u16) -> u8 {
+        let addr = if (0xE000..0xFE00).contains(&addr) {
             addr - 0x2000
         } else {
             addr
         };
+        let cgb = self.model == Model::Cgb;
         match addr {
-            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.mbc.read_rom(addr),
-            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B | 0xFF68..=0xFF6B => {
+            0x0000..=0x7FFF => self.mbc.read_rom(addr),
+            0xA000..=0xBFFF => self.mbc.read_ram(addr),
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF45 | 0xFF47..=0xFF4B => {
                 self.ppu.read(addr)
             }
-            0xC000..=0xCFFF | 0xE000..=0xEFFF => self.work_ram[(addr - 0xC000) as usize],
-            0xD000..=0xDFFF | 0xF000..=0xFDFF => {
+            0xC000..=0xCFFF => self.work_ram[(addr - 0xC000) as usize],
+            0xD000..=0xDFFF => {
                 self.work_ram[(self.wram_bank as usize * 0x1000) | addr as usize & 0x0FFF]
             }
+            0xFEA0..=0xFEFF => self.prohibited_read(addr),
             0xFF00 => self.joypad.read(),
             0xFF01..=0xFF02 => self.serial.read(addr),
-            0xFF0F => self.requested_interrupts(),
             0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF0F => 0xE0 | self.requested_interrupts(),
+            0xFF10..=0xFF3F => self.apu.read(addr),
+            0xFF46 => self.dma_source,
+            0xFF4D if cgb => {
+                (self.double_speed as u8) << 7 | 0x7E | self.speed_switch_armed as u8
+            }
+            0xFF4F if cgb => 0xFE | self.vram_bank,
+            0xFF68 if cgb => 0x40 | self.bg_palette_index,
+            0xFF69 if cgb => self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize],
+            0xFF6A if cgb => 0x40 | self.obj_palette_index,
+            0xFF6B if cgb => self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize],
+            0xFF70 if cgb => 0xF8 | self.wram_bank,
             0xFF80..=0xFFFE => self.high_ram[(addr - 0xFF80) as usize],
             0xFFFF => self.enabled_interrupts(),
-            _ => panic!("Read from unimplemented memory address: {:02X?}", addr),
+            // unused IO and CGB registers on DMG
+            0xFF03 | 0xFF08..=0xFF0E | 0xFF4C..=0xFF7F => 0xFF,
+            0xE000..=0xFDFF => unreachable!(),
+        }
+    }
+}
+
+impl<MBC> Memory for MappedMemory<MBC>
+where
+    MBC: Mbc,
+{
+    fn get(&self, addr: u16) -> u8 {
+        if let Some(dma) = &self.dma {
+            // the CPU only has its own bus to IO and HRAM. OAM is busy being written and
+            // everything else reads the byte the DMA is transferring
+            match addr {
+                0xFF00..=0xFFFF => {}
+                0xFE00..=0xFEFF => return 0xFF,
+                _ => return self.read(dma.addr()),
+            }
         }
+        self.read(addr)
     }
 
     fn write(&mut self, mut addr: u16, value: u8) {
+        if self.dma.is_some() && addr < 0xFF00 {
+            debug!("Write to {:#06X} ignored during DMA", addr);
+            return;
+        }
         // debug!("Updating memory at {:02X?} to {:02X?}", addr, result);
-        if addr > 0xE000 && addr < 0xFE00 {
+        if (0xE000..0xFE00).contains(&addr) {
             addr -= 0x2000;
         }
+        let cgb = self.model == Model::Cgb;
         match addr {
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.mbc.write(addr, value),
             0xFF46 => self.dma_transfer(value),
-            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B | 0xFF68..=0xFF6B => {
+            0xFF41 if !cgb => {
+                self.ppu.spurious_stat_interrupt();
                 self.ppu.write(addr, value)
             }
-            0xC000..=0xCFFF | 0xE000..=0xEFFF => self.work_ram[(addr - 0xC000) as usize] = value,
-            0xD000..=0xDFFF | 0xF000..=0xFDFF => {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B => self.ppu.write(addr, value),
+            0xC000..=0xCFFF => self.work_ram[(addr - 0xC000) as usize] = value,
+            0xD000..=0xDFFF => {
                 self.work_ram[(self.wram_bank as usize * 0x1000) | addr as usize & 0x0FFF] = value
             }
             0xFF00 => self.joypad.write(value),
             0xFF01..=0xFF02 => self.serial.write(addr, value),
             0xFF04..=0xFF07 => self.timer.write(addr, value),
-            0xFF0F => self.int_request = value,
-            0xFF10..=0xFF3F => { /* audio */ }
+            0xFF0F => self.int_request = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write(addr, value),
+            0xFF4D if cgb => self.speed_switch_armed = value & 1 != 0,
+            0xFF4F if cgb => self.vram_bank = value & 1,
+            0xFF68 if cgb => self.bg_palette_index = value & 0xBF,
+            0xFF69 if cgb => {
+                Self::write_palette(&mut self.bg_palette_index, &mut self.bg_palette_ram, value)
+            }
+            0xFF6A if cgb => self.obj_palette_index = value & 0xBF,
+            0xFF6B if cgb => {
+                Self::write_palette(&mut self.obj_palette_index, &mut self.obj_palette_ram, value)
+            }
+            0xFF70 if cgb => self.wram_bank = (value & 0x07).max(1),
             0xFF80..=0xFFFE => self.high_ram[(addr - 0xFF80) as usize] = value,
             0xFFFF => {
                 debug!("Setting interrupt enable to {:08b}", value);
                 self.int_enable = value
             }
-            0xFEA0..=0xFEFF => { /* Unusable memory */ }
-            _ => warn!("Write to unimplemented memory address: {:02X?}", addr),
+            // unusable memory, unused IO and CGB registers on DMG
+            0xFEA0..=0xFEFF | 0xFF03 | 0xFF08..=0xFF0E | 0xFF4C..=0xFF7F => {}
+            0xE000..=0xFDFF => unreachable!(),
         }
     }
 
@@ -315,11 +521,17 @@ where
     }
 
     fn cycle(&mut self) { // one machine cycle
+        self.dma_cycle();
         let interrupt1 = self.timer.cycle();
         if let Some(interrupt) = interrupt1 {
             self.request_interrupt(u8::from(interrupt));
         }
-        for _ in 0..4 {
+        self.apu_divider = !self.apu_divider;
+        if !self.double_speed || self.apu_divider {
+            self.apu.cycle();
+        }
+        let dots = if self.double_speed { 2 } else { 4 };
+        for _ in 0..dots {
             self.ppu.cycle();
             if self.ppu.interrupt != 0 {
                 self.request_interrupt(self.ppu.interrupt);
@@ -362,7 +574,39 @@ where
         self.int_request &= !u8::from(interrupt);
     }
 
-    fn control_msg(&mut self, msg: ControlMsg) {
+    fn button_pressed(&self) -> bool {
+        self.joypad.any_pressed()
+    }
+
+    fn stop(&mut self) -> bool {
+        self.timer.write(0xFF04, 0);
+        if self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            self.double_speed = !self.double_speed;
+            return true;
+        }
+        false
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        self.mbc.rom_bank(addr)
+    }
+
+    fn inc_dec_bus(&mut self, addr: u16) {
+        if self.model == Model::Dmg && (0xFE00..=0xFEFF).contains(&addr) {
+            self.ppu.corrupt_oam();
+        }
+    }
+
+    fn dma_active(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    fn dma_starting(&self) -> bool {
+        self.dma_start.is_some()
+    }
+
+    fn control_msg(&mut self, msg: ControlMsg) -> Result<(), Error> {
         debug!("Received control message: {:?}", msg);
         match msg {
             ControlMsg::Debug => {
@@ -371,10 +615,83 @@ where
             ControlMsg::ShowVRam(show) => {
                 self.ppu.show_vram = show;
             }
+            ControlMsg::BlankWhenLcdOff(blank) => self.ppu.blank_when_off = blank,
             ControlMsg::KeyDown(key) => self.joypad.keydown(key),
             ControlMsg::KeyUp(key) => self.joypad.keyup(key),
-            _ => panic!("Unhandled control message: {:?}", msg),
+            ControlMsg::MuteChannel(channel, muted) => self.apu.set_muted(channel, muted),
+            ControlMsg::SoloChannel(channel, solo) => self.apu.set_solo(channel, solo),
+            _ => return Err(Error::UnhandledControlMsg(msg)),
         }
+        Ok(())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.set_rom_checksum(self.rom_checksum);
+        state.set_thumbnail(self.ppu.framebuffer());
+        state.section(b"MEM ");
+        state.bytes(&self.work_ram);
+        state.bytes(&self.high_ram);
+        state.u8(self.wram_bank);
+        state.u8(self.vram_bank);
+        state.u8(self.dma_source);
+        state.bool(self.dma.is_some());
+        let (source, index) = self.dma.as_ref().map_or((0, 0), |dma| (dma.source, dma.index));
+        state.u16(source);
+        state.u16(index);
+        state.bool(self.dma_start.is_some());
+        let (page, delay) = self.dma_start.unwrap_or_default();
+        state.u8(page);
+        state.u8(delay);
+        state.u8(self.bg_palette_index);
+        state.u8(self.obj_palette_index);
+        state.bytes(&self.bg_palette_ram);
+        state.bytes(&self.obj_palette_ram);
+        state.u8(self.int_enable);
+        state.u8(self.int_request);
+        state.bool(self.model == Model::Cgb);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.apu_divider);
+        self.joypad.save(state);
+        self.ppu.save(state);
+        self.timer.save(state);
+        self.apu.save(state);
+        self.serial.save(state);
+        self.mbc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        if state.header().rom_checksum != self.rom_checksum {
+            return Err(Error::SaveStateForOtherRom);
+        }
+        state.section(b"MEM ")?;
+        state.bytes_into(&mut self.work_ram)?;
+        state.bytes_into(&mut self.high_ram)?;
+        self.wram_bank = (state.u8()? & 0x07).max(1);
+        self.vram_bank = state.u8()? & 1;
+        self.dma_source = state.u8()?;
+        let dma = state.bool()?;
+        let (source, index) = (state.u16()?, state.u16()?.min(0x9F));
+        self.dma = dma.then_some(OamDma { source, index });
+        let dma_start = state.bool()?;
+        let start = (state.u8()?, state.u8()?);
+        self.dma_start = dma_start.then_some(start);
+        self.bg_palette_index = state.u8()?;
+        self.obj_palette_index = state.u8()?;
+        state.bytes_into(&mut self.bg_palette_ram)?;
+        state.bytes_into(&mut self.obj_palette_ram)?;
+        self.int_enable = state.u8()?;
+        self.int_request = state.u8()?;
+        self.model = if state.bool()? { Model::Cgb } else { Model::Dmg };
+        self.double_speed = state.bool()?;
+        self.speed_switch_armed = state.bool()?;
+        self.apu_divider = state.bool()?;
+        self.joypad.load(state)?;
+        self.ppu.load(state)?;
+        self.timer.load(state)?;
+        self.apu.load(state)?;
+        self.serial.load(state)?;
+        self.mbc.load_state(state)
     }
 }
 
@@ -401,12 +718,21 @@ impl<const SIZE: usize> LinearMemory<SIZE> {
 }
 
 impl<const SIZE: usize> Memory for LinearMemory<SIZE> {
+    // IE and IF are mapped like on hardware, so that the CPU can overwrite them through the bus
     fn get(&self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+        match addr {
+            0xFF0F => self.int_request,
+            0xFFFF => self.int_enable,
+            _ => self.mem[addr as usize],
+        }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
-        self.mem[addr as usize] = value;
+        match addr {
+            0xFF0F => self.int_request = value,
+            0xFFFF => self.int_enable = value,
+            _ => self.mem[addr as usize] = value,
+        }
     }
 
     fn update<F>(&mut self, addr: u16, closure: F)
@@ -447,7 +773,131 @@ impl<const SIZE: usize> Memory for LinearMemory<SIZE> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusAccess {
+    Read { cycle: usize, addr: u16, value: u8 },
+    Write { cycle: usize, addr: u16, value: u8 },
+}
+
+// wraps another memory and logs every bus access with the machine cycle it happened in,
+// used to check the timing of the CPU in tests
+pub struct RecordingMemory<M: Memory> {
+    pub inner: M,
+    cycle: usize,
+    log: RefCell<Vec<BusAccess>>,
+}
+
+impl<M: Memory> RecordingMemory<M> {
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            cycle: 0,
+            log: RefCell::new(Vec::new()),
+        }
+    }
+
+    // returns the accesses logged so far and restarts counting cycles from 0
+    pub fn take_log(&mut self) -> Vec<BusAccess> {
+        self.cycle = 0;
+        self.log.take()
+    }
+}
+
+impl<M: Memory> Memory for RecordingMemory<M> {
+    fn get(&self, addr: u16) -> u8 {
+        let value = self.inner.get(addr);
+        self.log.borrow_mut().push(BusAccess::Read {
+            cycle: self.cycle,
+            addr,
+            value,
+        });
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.log.borrow_mut().push(BusAccess::Write {
+            cycle: self.cycle,
+            addr,
+            value,
+        });
+        self.inner.write(addr, value);
+    }
+
+    fn update<F>(&mut self, addr: u16, closure: F)
+    where
+        F: FnOnce() -> u8,
+    {
+        let result = closure();
+        self.write(addr, result);
+    }
+
+    fn cycle(&mut self) {
+        self.cycle += 1;
+        self.inner.cycle();
+    }
+
+    fn enable_interrupt(&mut self, interrupt: Interrupt, enable: bool) {
+        self.inner.enable_interrupt(interrupt, enable);
+    }
+
+    fn enabled_interrupts(&self) -> u8 {
+        self.inner.enabled_interrupts()
+    }
+
+    fn request_interrupt(&mut self, interrupt: u8) {
+        self.inner.request_interrupt(interrupt);
+    }
+
+    fn requested_interrupts(&self) -> u8 {
+        self.inner.requested_interrupts()
+    }
+
+    fn set_requested_interrupts(&mut self, value: u8) {
+        self.inner.set_requested_interrupts(value);
+    }
+
+    fn clear_requested_interrupt(&mut self, interrupt: Interrupt) {
+        self.inner.clear_requested_interrupt(interrupt);
+    }
+
+    fn button_pressed(&self) -> bool {
+        self.inner.button_pressed()
+    }
+
+    fn stop(&mut self) -> bool {
+        self.inner.stop()
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        self.inner.rom_bank(addr)
+    }
+
+    fn inc_dec_bus(&mut self, addr: u16) {
+        self.inner.inc_dec_bus(addr);
+    }
+
+    fn dma_active(&self) -> bool {
+        self.inner.dma_active()
+    }
+
+    fn dma_starting(&self) -> bool {
+        self.inner.dma_starting()
+    }
+
+    fn control_msg(&mut self, msg: ControlMsg) -> Result<(), Error> {
+        self.inner.control_msg(msg)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.inner.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.inner.load_state(state)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Interrupt {
     VBlank,
     LcdStat,
@@ -456,6 +906,34 @@ pub enum Interrupt {
     Joypad,
 }
 
+impl Interrupt {
+    // in order of priority
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x0040,
+            Interrupt::LcdStat => 0x0048,
+            Interrupt::Timer => 0x0050,
+            Interrupt::Serial => 0x0058,
+            Interrupt::Joypad => 0x0060,
+        }
+    }
+
+    // iterates over the interrupts whose bits are set, highest priority first
+    pub fn pending(bits: u8) -> impl Iterator<Item = Interrupt> {
+        Self::ALL
+            .into_iter()
+            .filter(move |&interrupt| bits & u8::from(interrupt) != 0)
+    }
+}
+
 impl From<Interrupt> for u8 {
     fn from(interrupt: Interrupt) -> u8 {
         match interrupt {
@@ -468,15 +946,17 @@ impl From<Interrupt> for u8 {
     }
 }
 
-impl From<u8> for Interrupt {
-    fn from(value: u8) -> Interrupt {
+impl TryFrom<u8> for Interrupt {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Interrupt, Error> {
         match value {
-            0b0001 => Interrupt::VBlank,
-            0b0010 => Interrupt::LcdStat,
-            0b0100 => Interrupt::Timer,
-            0b1000 => Interrupt::Serial,
-            0b10000 => Interrupt::Joypad,
-            _ => panic!("Invalid interrupt value: {:02X?}", value),
+            0b0001 => Ok(Interrupt::VBlank),
+            0b0010 => Ok(Interrupt::LcdStat),
+            0b0100 => Ok(Interrupt::Timer),
+            0b1000 => Ok(Interrupt::Serial),
+            0b10000 => Ok(Interrupt::Joypad),
+            _ => Err(Error::InvalidInterrupt(value)),
         }
     }
 }
@@ -1,11 +1,13 @@
 // pixel processing unit
 
+use crate::error::Error;
+use crate::frame::FrameWriter;
 use crate::memory::{Interrupt, Mbc};
+use crate::savestate::{Snapshot, StateReader, StateWriter};
 use bitflags::bitflags;
-use eframe::egui::Color32;
 use log::info;
 use std::cmp::PartialEq;
-use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
 
 bitflags! {
     pub struct LcdStat: u8 {
@@ -46,6 +48,15 @@ enum PpuMode {
     VBlank = 1,
 }
 impl PpuMode {
+    fn from_bits(bits: u8) -> PpuMode {
+        match bits & 0b11 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            _ => PpuMode::DrawingPixels,
+        }
+    }
+
     fn next(&self) -> PpuMode {
         match self {
             PpuMode::OamScan => PpuMode::DrawingPixels,
@@ -58,6 +69,33 @@ impl PpuMode {
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
+const DOTS_PER_LINE: usize = 456;
+const OAM_SCAN_DOTS: usize = 80;
+
+// palette colours to greys
+const SHADES: [u8; 4] = [255, 192, 96, 0];
+
+// an RGBA pixel of the frames handed to the frontend
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Rgba {
+    pub const WHITE: Rgba = Rgba::gray(255);
+    pub const BLACK: Rgba = Rgba::gray(0);
+
+    pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub const fn gray(value: u8) -> Self {
+        Self::new(value, value, value, 255)
+    }
+}
 
 struct Palette {
     id_0: u8,
@@ -66,6 +104,18 @@ struct Palette {
     id_3: u8,
 }
 
+impl Palette {
+    // the palette colour a colour ID is shown in
+    fn apply(&self, id: u8) -> u8 {
+        match id {
+            0 => self.id_0,
+            1 => self.id_1,
+            2 => self.id_2,
+            _ => self.id_3,
+        }
+    }
+}
+
 impl Default for Palette {
     fn default() -> Self {
         Self {
@@ -77,8 +127,62 @@ impl Default for Palette {
     }
 }
 
+// an object the OAM scan found on the current line
+#[derive(Copy, Clone, Default)]
+struct LineObject {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    fetched: bool,
+}
+
+// a pixel waiting in the object FIFO
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    color: u8,
+    palette: bool,     // OBP1 instead of OBP0
+    bg_priority: bool, // background colours 1-3 are drawn over it
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+// background and window tile fetcher. Every step but the push takes two dots, the push waits for
+// the background FIFO to be empty
+struct Fetcher {
+    step: FetcherStep,
+    dots: u8,
+    x: u8, // tile column, counted from the left edge of the screen or the window
+    window: bool,
+    tile: u8,
+    low: u8,
+    high: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Self {
+        Self {
+            step: FetcherStep::Tile,
+            dots: 0,
+            x: 0,
+            window,
+            tile: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
 pub struct Ppu {
     pub show_vram: bool,  // for debugging purposes
+    // show white instead of the last frame while the LCD is off
+    pub blank_when_off: bool,
     mode: PpuMode,
     pub mode_counter: usize,
     framebuffer: [u8; 160 * 144 * 4],
@@ -117,11 +221,24 @@ pub struct Ppu {
     hblank: bool,
     vblank: bool,
     win_y_trigger: bool,
+    lcd_starting: bool, // the first line after turning on the LCD has no OAM scan
+    skip_frame: bool,   // the first frame after turning on the LCD isn't shown
+    window_line: u8,    // internal line counter, only advances on lines that show the window
+    bg_fifo: VecDeque<u8>,
+    obj_fifo: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+    lx: u8,                         // pixels sent to the LCD on the current line
+    discard: u8,                    // pixels still to be dropped for fine scrolling
+    startup: u8,                    // dots left of the dummy fetch at the start of a line
+    obj_fetch: Option<(usize, u8)>, // line object being fetched and dots left
+    obj_wait_tile: Option<u8>,      // background tile an object fetch last waited for
+    line_objects: [LineObject; 10],
+    line_object_count: usize,
+    frames: u64,     // VBlanks since power on
+    stat_line: bool, // OR of all enabled STAT interrupt sources, interrupts fire on its rising edge
     pub(crate) interrupt: u8,
-    pub displaybuffer: Arc<Mutex<Vec<Color32>>>,
-    pub debug_displaybuffer: Arc<Mutex<Vec<Color32>>>,
-    pub displaybuffer_dirty: Arc<Mutex<bool>>,
-    pub debug_displaybuffer_dirty: Arc<Mutex<bool>>,
+    output: FrameWriter,
+    debug_output: FrameWriter,
 }
 
 // makes it easier to work with tiles, directly converts weird 16 byte format to 8x8 pixel format
@@ -163,22 +280,16 @@ impl Tile {
 }
 
 impl Ppu {
-    pub fn new(
-        displaybuffer: Arc<Mutex<Vec<Color32>>>,
-        debug_displaybuffer: Arc<Mutex<Vec<Color32>>>,
-        displaybuffer_dirty: Arc<Mutex<bool>>,
-        debug_displaybuffer_dirty: Arc<Mutex<bool>>,
-    ) -> Self {
+    pub fn new(output: FrameWriter, debug_output: FrameWriter) -> Self {
         Self {
             show_vram: true,  // for debugging purposes
+            blank_when_off: false,
             mode: PpuMode::HBlank,
             mode_counter: 0,
             framebuffer: [255; 160 * 144 * 4],
             debug_framebuffer: Box::new([255; 160 * 144 * 4]),
-            displaybuffer,
-            displaybuffer_dirty,
-            debug_displaybuffer,
-            debug_displaybuffer_dirty,
+            output,
+            debug_output,
             line: 0,
             lyc: 0,
             bg_win_enable: false,
@@ -203,104 +314,438 @@ impl Ppu {
             vram: [0; 0x2000],
             oam: [0; 0xa0],
             win_y_trigger: false,
+            lcd_starting: false,
+            skip_frame: false,
+            window_line: 0,
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::new(false),
+            lx: 0,
+            discard: 0,
+            startup: 0,
+            obj_fetch: None,
+            obj_wait_tile: None,
+            line_objects: [LineObject::default(); 10],
+            line_object_count: 0,
             tiles: core::array::from_fn(|_| Tile::from_raw([0; 16])),
             tile_map_0: [0; 0x400],
             tile_map_1: [0; 0x400],
+            frames: 0,
+            stat_line: false,
             interrupt: 0,
             hblank: false,
             vblank: false,
         }
     }
 
+    // one dot
     pub fn cycle(&mut self) {
-        puffin::profile_function!();
+        profile_function!();
+        if !self.lcd_enable {
+            return;
+        }
         self.mode_counter += 1;
-        if self.mode_counter >= 114 {
+        if self.mode_counter >= DOTS_PER_LINE {
             // time it takes to render a scanline
-            self.mode_counter -= 114;
+            self.mode_counter -= DOTS_PER_LINE;
             self.line = (self.line + 1) % 154;
-            if self.lyc_int && self.line == self.lyc {
-                self.interrupt |= u8::from(Interrupt::LcdStat);
-            }
             if self.line >= 144 && self.mode != PpuMode::VBlank {
                 self.interrupt |= self.set_mode(PpuMode::VBlank);
             }
         }
 
         if self.line < 144 {
-            if self.mode_counter <= 20 {
-                if self.mode != PpuMode::OamScan {
+            if self.mode == PpuMode::DrawingPixels {
+                // mode 3 lasts until all 160 pixels are out, which depends on scrolling, the
+                // window and objects
+                if self.draw_dot() {
+                    self.interrupt |= self.set_mode(PpuMode::HBlank);
+                }
+            } else if self.mode_counter < OAM_SCAN_DOTS {
+                if self.mode != PpuMode::OamScan && !self.lcd_starting {
                     self.interrupt |= self.set_mode(PpuMode::OamScan);
                 }
-            } else if self.mode_counter <= 63 {
-                if self.mode != PpuMode::DrawingPixels {
-                    self.interrupt |= self.set_mode(PpuMode::DrawingPixels);
+            } else if self.mode == PpuMode::OamScan || self.lcd_starting {
+                self.interrupt |= self.set_mode(PpuMode::DrawingPixels);
+            }
+        }
+        self.update_stat_line();
+    }
+
+    // LY as the LY=LYC comparator sees it. It sees nothing for a machine cycle while LY changes,
+    // and LY already drops to 0 early in line 153
+    fn ly_compare(&self) -> Option<u8> {
+        match (self.line, self.mode_counter) {
+            (0, _) => Some(0),
+            (153, 4..=7) => Some(153),
+            (153, 8..) => Some(0),
+            (_, 0..=3) => None,
+            (line, _) => Some(line),
+        }
+    }
+
+    // the LY register, which reads 0 for most of line 153
+    fn ly(&self) -> u8 {
+        if self.line == 153 && self.mode_counter >= 4 {
+            0
+        } else {
+            self.line
+        }
+    }
+
+    fn update_stat_line(&mut self) {
+        let line = (self.lyc_int && self.ly_compare() == Some(self.lyc))
+            || match self.mode {
+                PpuMode::HBlank => self.mode_0_int && !self.lcd_starting,
+                PpuMode::VBlank => self.mode_1_int,
+                PpuMode::OamScan => self.mode_2_int,
+                PpuMode::DrawingPixels => false,
+            };
+        if line && !self.stat_line {
+            self.interrupt |= u8::from(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    // on DMG, writing STAT briefly enables every source it can, which raises an interrupt in
+    // HBlank, VBlank and on LY=LYC unless the line is already high
+    pub(crate) fn spurious_stat_interrupt(&mut self) {
+        if !self.lcd_enable || self.stat_line {
+            return;
+        }
+        let line = self.ly_compare() == Some(self.lyc)
+            || (self.mode == PpuMode::HBlank && !self.lcd_starting)
+            || self.mode == PpuMode::VBlank;
+        if line {
+            self.interrupt |= u8::from(Interrupt::LcdStat);
+            self.stat_line = true;
+        }
+    }
+
+    // picks the first 10 objects in OAM order that cover the current line
+    fn scan_oam(&mut self) {
+        self.line_object_count = 0;
+        for obj in self.oam.chunks_exact(4) {
+            if self.line_object_count == self.line_objects.len() {
+                break;
+            }
+            let row = self.line as i16 + 16 - obj[0] as i16;
+            if (0..self.obj_size as i16).contains(&row) {
+                self.line_objects[self.line_object_count] = LineObject {
+                    y: obj[0],
+                    x: obj[1],
+                    tile: obj[2],
+                    flags: obj[3],
+                    fetched: false,
+                };
+                self.line_object_count += 1;
+            }
+        }
+    }
+
+    fn start_drawing(&mut self) {
+        self.lcd_starting = false;
+        self.scan_oam();
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetcher = Fetcher::new(false);
+        self.lx = 0;
+        self.discard = self.viewport_x % 8;
+        self.startup = 6;
+        self.obj_fetch = None;
+        self.obj_wait_tile = None;
+    }
+
+    // one dot of mode 3, returns true once the line is complete
+    fn draw_dot(&mut self) -> bool {
+        if self.startup > 0 {
+            // the first tile is fetched once and thrown away
+            self.startup -= 1;
+            return false;
+        }
+        if let Some((index, dots)) = self.obj_fetch {
+            if dots == 1 {
+                self.obj_fetch = None;
+                self.fetch_object(index);
+            } else {
+                self.obj_fetch = Some((index, dots - 1));
+            }
+            return false;
+        }
+        if self.discard == 0 {
+            if let Some(index) = self.next_object() {
+                // 6 dots for the fetch, plus up to 5 waiting for the background tile under the
+                // object unless an earlier object already waited for it
+                let x = self.lx.wrapping_add(self.viewport_x);
+                let wait = if self.obj_wait_tile == Some(x / 8) {
+                    0
+                } else {
+                    5u8.saturating_sub(x % 8)
+                };
+                self.obj_wait_tile = Some(x / 8);
+                self.obj_fetch = Some((index, 6 + wait));
+                return self.draw_dot();
+            }
+            if self.window_starts() {
+                self.bg_fifo.clear();
+                self.fetcher = Fetcher::new(true);
+                // with WX below 7 the left edge of the window is off screen
+                self.discard = 7u8.saturating_sub(self.window_x);
+            }
+        }
+        self.step_fetcher();
+        let Some(color) = self.bg_fifo.pop_front() else {
+            return false;
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return false;
+        }
+        let obj = self.obj_fifo.pop_front();
+        self.push_pixel(color, obj);
+        self.lx += 1;
+        self.lx == SCREEN_WIDTH as u8
+    }
+
+    // the next object whose left edge has been reached
+    fn next_object(&self) -> Option<usize> {
+        if !self.obj_enable {
+            return None;
+        }
+        // the lowest X goes first so it ends up on top, OAM order breaks ties
+        self.line_objects[..self.line_object_count]
+            .iter()
+            .enumerate()
+            .filter(|(_, obj)| !obj.fetched && obj.x <= self.lx + 8)
+            .min_by_key(|(_, obj)| obj.x)
+            .map(|(index, _)| index)
+    }
+
+    // the window takes over once the pixel at WX - 7 is next, WX 166 only shows it in the last
+    // column and anything higher hides it
+    fn window_starts(&self) -> bool {
+        self.win_enable
+            && self.win_y_trigger
+            && !self.fetcher.window
+            && self.lx as u16 + 7 >= self.window_x as u16
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fetcher.step == FetcherStep::Push {
+            if self.bg_fifo.is_empty() {
+                for bit in (0..8).rev() {
+                    let low = (self.fetcher.low >> bit) & 1;
+                    let high = (self.fetcher.high >> bit) & 1;
+                    self.bg_fifo.push_back(high << 1 | low);
                 }
-            } else if self.mode != PpuMode::HBlank {
-                self.interrupt |= self.set_mode(PpuMode::HBlank);
+                self.fetcher.x += 1;
+                self.fetcher.step = FetcherStep::Tile;
             }
+            return;
+        }
+        self.fetcher.dots += 1;
+        if self.fetcher.dots < 2 {
+            return;
+        }
+        self.fetcher.dots = 0;
+        match self.fetcher.step {
+            FetcherStep::Tile => {
+                self.fetcher.tile = self.vram[self.tile_map_addr()];
+                self.fetcher.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fetcher.low = self.vram[self.tile_data_addr()];
+                self.fetcher.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fetcher.high = self.vram[self.tile_data_addr() + 1];
+                self.fetcher.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => unreachable!(),
         }
     }
 
+    // VRAM offset of the tile map entry the fetcher reads
+    fn tile_map_addr(&self) -> usize {
+        let (map, column, y) = if self.fetcher.window {
+            (self.win_tile_map, self.fetcher.x, self.window_line)
+        } else {
+            (
+                self.bg_tile_map,
+                self.viewport_x / 8 + self.fetcher.x,
+                self.line.wrapping_add(self.viewport_y),
+            )
+        };
+        let base = if map { 0x1C00 } else { 0x1800 };
+        base + (y as usize / 8) * 32 + (column as usize & 31)
+    }
+
+    // VRAM offset of the low byte of the tile row the fetcher reads
+    fn tile_data_addr(&self) -> usize {
+        let y = if self.fetcher.window {
+            self.window_line
+        } else {
+            self.line.wrapping_add(self.viewport_y)
+        };
+        let tile = if self.bg_win_tile_data {
+            self.fetcher.tile as usize * 16
+        } else {
+            (0x1000 + self.fetcher.tile as i8 as isize * 16) as usize
+        };
+        tile + (y as usize % 8) * 2
+    }
+
+    // loads the row of an object into the object FIFO, pixels that are already there from
+    // objects fetched earlier win over it
+    fn fetch_object(&mut self, index: usize) {
+        let obj = &mut self.line_objects[index];
+        obj.fetched = true;
+        let obj = *obj;
+        // the height can change after the OAM scan, the row then wraps within the new one
+        let mut row = (self.line + 16 - obj.y) as usize & (self.obj_size as usize - 1);
+        if obj.flags & 0b01000000 != 0 {
+            row = self.obj_size as usize - 1 - row;
+        }
+        // tall objects are made of an even tile and the one after it
+        let tile = if self.obj_size == 16 {
+            obj.tile & 0xFE
+        } else {
+            obj.tile
+        };
+        let addr = tile as usize * 16 + row * 2;
+        let (low, high) = (self.vram[addr], self.vram[addr + 1]);
+        // objects partly off the left edge start in the middle
+        let skip = (self.lx + 8 - obj.x) as usize;
+        for i in skip..8 {
+            let bit = if obj.flags & 0b00100000 != 0 {
+                i
+            } else {
+                7 - i
+            };
+            let pixel = ObjPixel {
+                color: ((high >> bit) & 1) << 1 | (low >> bit) & 1,
+                palette: obj.flags & 0b00010000 != 0,
+                bg_priority: obj.flags & 0b10000000 != 0,
+            };
+            let slot = i - skip;
+            match self.obj_fifo.get_mut(slot) {
+                Some(existing) if existing.color == 0 => *existing = pixel,
+                Some(_) => {}
+                None => self.obj_fifo.push_back(pixel),
+            }
+        }
+    }
+
+    fn push_pixel(&mut self, color: u8, obj: Option<ObjPixel>) {
+        let bg = if self.bg_win_enable { color } else { 0 };
+        // colour 0 of objects is transparent, objects behind the background only show through
+        // its colour 0
+        let obj = obj.filter(|obj| obj.color != 0 && !(obj.bg_priority && bg != 0));
+        let color = match obj {
+            Some(obj) if obj.palette => self.obj_palette_1.apply(obj.color),
+            Some(obj) => self.obj_palette_0.apply(obj.color),
+            None => self.bg_palette.apply(bg),
+        };
+        let shade = SHADES[color as usize];
+        set_pixel!(self, self.lx, shade, shade, shade, 255);
+    }
+
+    // the last complete frame as RGBA once VBlank starts, the frame being drawn otherwise
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // the CPU can't access VRAM while the PPU is drawing
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_enable || self.mode != PpuMode::DrawingPixels
+    }
+
+    // the CPU can't access OAM while the PPU scans or draws
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_enable || !matches!(self.mode, PpuMode::OamScan | PpuMode::DrawingPixels)
+    }
+
+    // DMG OAM corruption bug, triggered by the CPU putting an OAM address on the bus with a 16-bit
+    // increment or decrement while the PPU scans OAM. The 8 byte row the PPU is reading is mixed
+    // with the row before it, which is then copied over the rest of the row
+    pub fn corrupt_oam(&mut self) {
+        if !self.lcd_enable || self.mode != PpuMode::OamScan {
+            return;
+        }
+        let row = (self.mode_counter.max(1) - 1) / 4;
+        if row == 0 {
+            return;
+        }
+        let word = |oam: &[u8; 0xa0], index: usize| {
+            u16::from_le_bytes([oam[index * 2], oam[index * 2 + 1]])
+        };
+        let a = word(&self.oam, row * 4);
+        let b = word(&self.oam, (row - 1) * 4);
+        let c = word(&self.oam, (row - 1) * 4 + 2);
+        let corrupted = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[row * 8..row * 8 + 2].copy_from_slice(&corrupted.to_le_bytes());
+        self.oam
+            .copy_within((row - 1) * 8 + 2..row * 8, row * 8 + 2);
+    }
+
     fn set_mode(&mut self, mode: PpuMode) -> u8 {
-        puffin::profile_function!();
+        profile_function!();
         self.mode = mode;
         self.vblank = false;
         self.hblank = false;
         match mode {
-            PpuMode::OamScan => {
-                if self.mode_2_int {
-                    u8::from(Interrupt::LcdStat)
-                } else {
-                    0
-                }
-            }
+            PpuMode::OamScan => 0,
             PpuMode::DrawingPixels => {
-                if self.win_enable && !self.win_y_trigger && self.line == self.window_y {
+                // the window shows from the first line that matches WY for the rest of the frame,
+                // even if it is only turned on later
+                if self.line == self.window_y {
                     self.win_y_trigger = true;
-                    self.window_y = u8::MAX;
                 }
+                self.start_drawing();
                 0
             }
             PpuMode::HBlank => {
-                self.render_scanline();
-                self.hblank = true;
-                if self.mode_0_int {
-                    u8::from(Interrupt::LcdStat)
-                } else {
-                    0
+                if self.fetcher.window {
+                    self.window_line += 1;
+                }
+                if self.show_vram {
+                    self.dump_vram();
                 }
+                self.hblank = true;
+                0
             }
             PpuMode::VBlank => {
-                self.post_frame();
-                self.win_y_trigger = false;
-                self.vblank = true;
-                if self.mode_1_int {
-                    u8::from(Interrupt::LcdStat) | u8::from(Interrupt::VBlank)
+                self.frames += 1;
+                if self.skip_frame {
+                    self.skip_frame = false;
                 } else {
-                    u8::from(Interrupt::VBlank)
+                    self.post_frame();
                 }
+                self.win_y_trigger = false;
+                self.window_line = 0;
+                self.vblank = true;
+                u8::from(Interrupt::VBlank)
             }
         }
     }
 
     fn post_frame(&mut self) {
-        puffin::profile_function!();
-        *self.displaybuffer.lock().unwrap() = self
-            .framebuffer
-            .chunks_exact(4)
-            .map(|pixel| Color32::from_rgba_unmultiplied(pixel[0], pixel[1], pixel[2], pixel[3]))
-            .collect::<Vec<_>>();
-        *self.displaybuffer_dirty.lock().unwrap() = true;
+        profile_function!();
+        Self::publish(&mut self.output, &self.framebuffer[..]);
         if self.show_vram {
-            *self.debug_displaybuffer.lock().unwrap() = self
-                .debug_framebuffer
-                .chunks_exact(4)
-                .map(|pixel| Color32::from_rgba_unmultiplied(pixel[0], pixel[1], pixel[2], pixel[3]))
-                .collect::<Vec<_>>();
-            *self.debug_displaybuffer_dirty.lock().unwrap() = true;
+            Self::publish(&mut self.debug_output, &self.debug_framebuffer[..]);
         }
+    }
 
+    fn publish(output: &mut FrameWriter, framebuffer: &[u8]) {
+        for (out, pixel) in output.buffer().iter_mut().zip(framebuffer.chunks_exact(4)) {
+            *out = Rgba::new(pixel[0], pixel[1], pixel[2], pixel[3]);
+        }
+        output.publish();
     }
 
     pub fn read(&self, addr: u16) -> u8 {
@@ -318,18 +763,18 @@ impl Ppu {
                 reg
             }
             0xff41 => {
-                let mut reg = 0;
+                let mut reg = 0x80; // bit 7 is unused and always set
                 reg |= (self.lyc_int as u8) << 6;
                 reg |= (self.mode_2_int as u8) << 5;
                 reg |= (self.mode_1_int as u8) << 4;
                 reg |= (self.mode_0_int as u8) << 3;
-                reg |= (self.lyc_int as u8) << 2;
+                reg |= ((self.ly_compare() == Some(self.lyc)) as u8) << 2;
                 reg |= self.mode as u8;
                 reg
             }
             0xff42 => self.viewport_y,
             0xff43 => self.viewport_x,
-            0xff44 => self.line,
+            0xff44 => self.ly(),
             0xff45 => self.lyc,
             0xff47 => {
                 let mut reg = 0;
@@ -357,7 +802,9 @@ impl Ppu {
             }
             0xff4a => self.window_y,
             0xff4b => self.window_x,
+            0x8000..=0x9fff if !self.vram_accessible() => 0xFF,
             0x8000..=0x9fff => self.vram[(addr - 0x8000) as usize],
+            0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             x => panic!("PPU read from unimplemented register: {:#06x}", x),
         }
@@ -380,14 +827,22 @@ impl Ppu {
                 let toggled_lcd_on = !initial_lcd_enable && self.lcd_enable;
                 if toggled_lcd_off {
                     info!("LCD turned off");
+                    // LY stays at 0 and STAT in mode 0 until the LCD is turned back on
                     self.line = 0;
-                    self.mode = PpuMode::OamScan;
+                    self.mode = PpuMode::HBlank;
+                    self.stat_line = false;
                     self.win_y_trigger = false;
+                    self.window_line = 0;
                     self.mode_counter = 0;
-                    self.clear_framebuffer(0);
+                    if self.blank_when_off {
+                        self.clear_framebuffer(255);
+                        self.post_frame();
+                    }
                 }
                 if toggled_lcd_on {
                     info!("LCD turned on");
+                    self.lcd_starting = true;
+                    self.skip_frame = true;
                 }
                 if self.bg_win_enable && !initial_bg_win_enable {
                     info!("Background and window rendering enabled");
@@ -401,13 +856,17 @@ impl Ppu {
                 self.mode_2_int = value & 0b00100000 != 0;
                 self.mode_1_int = value & 0b00010000 != 0;
                 self.mode_0_int = value & 0b00001000 != 0;
+                if self.lcd_enable {
+                    self.update_stat_line();
+                }
             }
             0xff42 => self.viewport_y = value,
             0xff43 => self.viewport_x = value,
+            0xff44 => {} // LY is read-only
             0xff45 => {
                 self.lyc = value;
-                if self.lyc_int && self.line == self.lyc {
-                    self.interrupt |= u8::from(Interrupt::LcdStat);
+                if self.lcd_enable {
+                    self.update_stat_line();
                 }
             }
             0xff47 => {
@@ -430,94 +889,31 @@ impl Ppu {
             }
             0xff4a => self.window_y = value,
             0xff4b => self.window_x = value,
-            0x8000..=0x97ff => self.tiles[(addr - 0x8000) as usize / 16]
-                .set_byte((addr - 0x8000) as usize % 16, value),
-            0x9800..=0x9bff => self.tile_map_0[(addr - 0x9800) as usize] = value,
-            0x9c00..=0x9fff => self.tile_map_1[(addr - 0x9c00) as usize] = value,
+            0x8000..=0x9fff if !self.vram_accessible() => {}
+            0xFE00..=0xFE9F if !self.oam_accessible() => {}
+            0x8000..=0x9fff => {
+                // raw VRAM for reads, decoded tiles and maps for rendering
+                self.vram[(addr - 0x8000) as usize] = value;
+                match addr {
+                    0x8000..=0x97ff => self.tiles[(addr - 0x8000) as usize / 16]
+                        .set_byte((addr - 0x8000) as usize % 16, value),
+                    0x9800..=0x9bff => self.tile_map_0[(addr - 0x9800) as usize] = value,
+                    _ => self.tile_map_1[(addr - 0x9c00) as usize] = value,
+                }
+            }
 
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = value,
             _ => unimplemented!("PPU write to unimplemented register: {:#06x}", addr),
         }
     }
-    pub fn render_scanline(&mut self) {
-        puffin::profile_function!();
-        self.clear_scanline(0);
-        if self.show_vram {
-            self.dump_vram();
-        }
-        if self.bg_win_enable {
-            self.render_background();
-        } else {
-            self.clear_scanline(255);
-        }
-        if self.obj_enable {
-            self.render_objects();
-        }
-        // self.dump_vram();
-        // self.render_sprites();
-    }
-
-    fn render_background(&mut self) {
-        puffin::profile_function!();
-        let tilemap = if self.bg_tile_map {
-            &self.tile_map_1
-        } else {
-            &self.tile_map_0
-        };
-        let scx = self.viewport_x;
-        let scy = self.viewport_y;
-
-        let tilemap_line = self.line as usize / 8;
-        for (i, tile_id) in tilemap[tilemap_line * 32..(tilemap_line + 1) * 32]
-            .iter()
-            .enumerate()
-        {
-            puffin::profile_scope!("Render bg tile");
-            let tile_index = if self.bg_win_tile_data {
-                *tile_id as usize
-            } else {
-                (0x100 + *tile_id as i8 as i16) as usize
-            };
-            let tile = self.tiles[tile_index].pixels;
-            for x in 0..8 {
-                let tile_line = scy as usize + self.line as usize % 8;
-                let color = match tile[tile_line * 8 + x] {
-                    0 => Color32::from_rgba_unmultiplied(255, 255, 255, 255),
-                    1 => Color32::from_rgba_unmultiplied(192, 192, 192, 255),
-                    2 => Color32::from_rgba_unmultiplied(96, 96, 96, 255),
-                    3 => Color32::from_rgba_unmultiplied(0, 0, 0, 255),
-                    _ => unreachable!(),
-                };
-                let draw_at = scx as usize + i * 8 + (8 - x);
-                if draw_at < SCREEN_WIDTH {
-                    set_pixel!(
-                        self,
-                        draw_at as u8,
-                        color.r(),
-                        color.g(),
-                        color.b(),
-                        color.a()
-                    );
-                }
-            }
-        }
-    }
-
     fn clear_framebuffer(&mut self, color: u8) {
-        puffin::profile_function!();
+        profile_function!();
         self.framebuffer = [color; 160 * 144 * 4];
     }
 
-    fn clear_scanline(&mut self, color: u8) {
-        puffin::profile_function!();
-        self.framebuffer
-            [self.line as usize * SCREEN_WIDTH * 4..(self.line as usize + 1) * SCREEN_WIDTH * 4]
-            .fill(color);
-    }
-
     // render all loaded sprites on the current scanline
     fn dump_vram(&mut self) {
-        puffin::profile_function!();
+        profile_function!();
         // tiles are 8x8. we are rendering just a single scanline in this function. lay out all tiles in a row, wrapping to the next row
         for tile_x in 0..20 {
             // can fit 20 tiles on screen
@@ -528,71 +924,19 @@ impl Ppu {
             for x in 0..8 {
                 let pixel = pixels[self.line as usize % 8 * 8 + x as usize];
                 let color = match pixel {
-                    0 => Color32::from_rgba_unmultiplied(255, 255, 255, 255),
-                    1 => Color32::from_rgba_unmultiplied(192, 192, 192, 255),
-                    2 => Color32::from_rgba_unmultiplied(96, 96, 96, 255),
-                    3 => Color32::from_rgba_unmultiplied(0, 0, 0, 255),
+                    0 => Rgba::gray(255),
+                    1 => Rgba::gray(192),
+                    2 => Rgba::gray(96),
+                    3 => Rgba::gray(0),
                     _ => unreachable!(),
                 };
                 set_dbg_pixel!(
                     self,
                     tile_x as u8 * 8 + x as u8,
-                    color.r(),
-                    color.g(),
-                    color.b(),
-                    color.a()
-                );
-            }
-        }
-    }
-
-    // renders all sprites on the current scanline
-    fn render_objects(&mut self) {
-        puffin::profile_function!();
-        // all objects that are visible on the current scanline
-        let draw = self
-            .oam
-            .chunks_exact(4)
-            .filter(|obj| {
-                let y = obj[0] as i32 - 16;
-                let x = obj[1] as i32 - 8;
-                y <= self.line as i32 && y + self.obj_size as i32 > self.line as i32
-            })
-            .collect::<Vec<_>>();
-        // draw.sort_by_key(|obj| obj[1]);  // todo do we need to sort this?
-        for obj in draw {
-            puffin::profile_scope!("Render sprite");
-            let x = obj[1] as i32 - 8; // sprite's position on screen
-            let y = obj[0] as i32 - 16;
-            let tile_id = obj[2];
-            let flags = obj[3];
-            let palette = flags & 0b00010000 != 0;
-            let flip_x = flags & 0b00100000 != 0;
-            let flip_y = flags & 0b01000000 != 0;
-            let priority = flags & 0b10000000 == 0; // 1 is above background
-            let tile = self.tiles[tile_id as usize].pixels;
-            for i in 0..8 {
-                let i = if flip_x { 7 - i } else { i };
-                let sprite_line = self.line as i32 - y;
-                let line = if flip_y { 7 - sprite_line } else { sprite_line };
-                let pixel = tile[line as usize * 8 + i as usize];
-                if pixel == 0 {
-                    continue;
-                }
-                let color = match pixel {
-                    0 => Color32::from_rgba_unmultiplied(255, 255, 255, 255),
-                    1 => Color32::from_rgba_unmultiplied(192, 192, 192, 255),
-                    2 => Color32::from_rgba_unmultiplied(96, 96, 96, 255),
-                    3 => Color32::from_rgba_unmultiplied(0, 0, 0, 255),
-                    _ => unreachable!(),
-                };
-                set_pixel!(
-                    self,
-                    x.wrapping_add(8 - i),
-                    color.r(),
-                    color.g(),
-                    color.b(),
-                    color.a()
+                    color.r,
+                    color.g,
+                    color.b,
+                    color.a
                 );
             }
         }
@@ -608,3 +952,157 @@ impl Ppu {
         }
     }
 }
+
+// LCDC, STAT, SCY, SCX, LYC, BGP, OBP0, OBP1, WY and WX, saved as they read
+const SAVED_REGISTERS: [u16; 10] = [
+    0xff40, 0xff41, 0xff42, 0xff43, 0xff45, 0xff47, 0xff48, 0xff49, 0xff4a, 0xff4b,
+];
+
+// the debug view and the frontend settings aren't saved
+impl Snapshot for Ppu {
+    fn save(&self, state: &mut StateWriter) {
+        state.section(b"PPU ");
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        state.bytes(&self.framebuffer);
+        for addr in SAVED_REGISTERS {
+            state.u8(self.read(addr));
+        }
+        state.u8(self.mode as u8);
+        state.u16(self.mode_counter as u16);
+        state.u8(self.line);
+        state.bool(self.win_y_trigger);
+        state.bool(self.lcd_starting);
+        state.bool(self.skip_frame);
+        state.u8(self.window_line);
+        state.u64(self.frames);
+        state.bool(self.stat_line);
+        state.u8(self.interrupt);
+
+        // mode 3 in progress
+        state.bytes(&self.bg_fifo.iter().copied().collect::<Vec<_>>());
+        state.u8(self.obj_fifo.len() as u8);
+        for pixel in &self.obj_fifo {
+            state.u8(pixel.color);
+            state.bool(pixel.palette);
+            state.bool(pixel.bg_priority);
+        }
+        state.u8(self.fetcher.step as u8);
+        state.u8(self.fetcher.dots);
+        state.u8(self.fetcher.x);
+        state.bool(self.fetcher.window);
+        state.u8(self.fetcher.tile);
+        state.u8(self.fetcher.low);
+        state.u8(self.fetcher.high);
+        state.u8(self.lx);
+        state.u8(self.discard);
+        state.u8(self.startup);
+        state.bool(self.obj_fetch.is_some());
+        let (index, dots) = self.obj_fetch.unwrap_or_default();
+        state.u8(index as u8);
+        state.u8(dots);
+        state.bool(self.obj_wait_tile.is_some());
+        state.u8(self.obj_wait_tile.unwrap_or_default());
+        state.u8(self.line_object_count as u8);
+        for obj in &self.line_objects[..self.line_object_count] {
+            state.u8(obj.y);
+            state.u8(obj.x);
+            state.u8(obj.tile);
+            state.u8(obj.flags);
+            state.bool(obj.fetched);
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.section(b"PPU ")?;
+        state.bytes_into(&mut self.vram)?;
+        for (index, tile) in self.tiles.iter_mut().enumerate() {
+            *tile = Tile::from_raw(self.vram[index * 16..index * 16 + 16].try_into().unwrap());
+        }
+        self.tile_map_0.copy_from_slice(&self.vram[0x1800..0x1C00]);
+        self.tile_map_1.copy_from_slice(&self.vram[0x1C00..0x2000]);
+        state.bytes_into(&mut self.oam)?;
+        state.bytes_into(&mut self.framebuffer)?;
+        // LCDC is written with the LCD already in its saved state, so that nothing is toggled
+        let lcdc = state.u8()?;
+        self.lcd_enable = lcdc & 0x80 != 0;
+        for addr in SAVED_REGISTERS {
+            let value = if addr == 0xff40 { lcdc } else { state.u8()? };
+            self.write(addr, value);
+        }
+        self.mode = PpuMode::from_bits(state.u8()?);
+        self.mode_counter = (state.u16()? as usize).min(DOTS_PER_LINE - 1);
+        self.line = state.u8()? % 154;
+        self.win_y_trigger = state.bool()?;
+        self.lcd_starting = state.bool()?;
+        self.skip_frame = state.bool()?;
+        self.window_line = state.u8()?;
+        self.frames = state.u64()?;
+        self.stat_line = state.bool()?;
+        self.interrupt = state.u8()?;
+
+        self.bg_fifo = state.bytes()?.iter().copied().collect();
+        self.obj_fifo.clear();
+        for _ in 0..state.u8()? {
+            self.obj_fifo.push_back(ObjPixel {
+                color: state.u8()?,
+                palette: state.bool()?,
+                bg_priority: state.bool()?,
+            });
+        }
+        self.fetcher.step = match state.u8()? {
+            0 => FetcherStep::Tile,
+            1 => FetcherStep::DataLow,
+            2 => FetcherStep::DataHigh,
+            _ => FetcherStep::Push,
+        };
+        self.fetcher.dots = state.u8()?;
+        self.fetcher.x = state.u8()?;
+        self.fetcher.window = state.bool()?;
+        self.fetcher.tile = state.u8()?;
+        self.fetcher.low = state.u8()?;
+        self.fetcher.high = state.u8()?;
+        self.lx = state.u8()?;
+        self.discard = state.u8()?;
+        self.startup = state.u8()?;
+        let fetching = state.bool()?;
+        let fetch = (state.u8()? as usize, state.u8()?);
+        self.obj_fetch = fetching.then_some(fetch);
+        let waited = state.bool()?;
+        let tile = state.u8()?;
+        self.obj_wait_tile = waited.then_some(tile);
+        self.line_object_count = (state.u8()? as usize).min(self.line_objects.len());
+        for obj in &mut self.line_objects[..self.line_object_count] {
+            *obj = LineObject {
+                y: state.u8()?,
+                x: state.u8()?,
+                tile: state.u8()?,
+                flags: state.u8()?,
+                fetched: state.bool()?,
+            };
+        }
+        // these index the line and the objects on it, an object is only fetched once reached
+        let fetch_index = self.obj_fetch.map(|(index, _)| index);
+        if self.lx as usize > SCREEN_WIDTH
+            || self.fetcher.x > 32
+            || fetch_index.is_some_and(|index| {
+                index >= self.line_object_count || self.line_objects[index].x > self.lx + 8
+            })
+        {
+            return Err(Error::InvalidSaveState(
+                "PPU is past the end of the line".to_string(),
+            ));
+        }
+        // the OAM scan only picks objects covering the line, tall ones cover 16 rows
+        let line = self.line as i16 + 16;
+        if self.line_objects[..self.line_object_count]
+            .iter()
+            .any(|obj| !(0..16).contains(&(line - obj.y as i16)))
+        {
+            return Err(Error::InvalidSaveState(
+                "object is not on the line".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
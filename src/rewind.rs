@@ -0,0 +1,270 @@
+// rewinding through save states taken every few frames. Only the newest snapshot is kept whole,
+// the others are stored as compressed XOR deltas against the snapshot after them
+
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RewindConfig {
+    pub seconds: u32,  // how far back rewinding goes
+    pub interval: u32, // frames between snapshots
+    pub memory: usize, // bytes the snapshots may take up, older ones are dropped first
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        Self {
+            seconds: 20,
+            interval: 2,
+            memory: 32 * 1024 * 1024,
+        }
+    }
+}
+
+pub struct RewindBuffer {
+    config: RewindConfig,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // oldest first
+    used: usize,
+    // reused between deltas, dropped deltas are kept to be filled again
+    compressed: Vec<u8>,
+    xor: Vec<u8>,
+    spare: Vec<Vec<u8>>,
+}
+
+// dropped deltas kept around for new ones
+const SPARE_DELTAS: usize = 4;
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            latest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+            compressed: Vec::new(),
+            xor: Vec::new(),
+            spare: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    // snapshots held, including the newest
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // bytes taken up by the newest snapshot and the deltas
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.used += state.len();
+        if let Some(previous) = self.latest.replace(state) {
+            self.used -= previous.len();
+            let delta = self.spare.pop().unwrap_or_default();
+            let newer = self.latest.as_ref().unwrap();
+            let delta = compress_delta(newer, previous, &mut self.compressed, delta);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+        }
+        // the newest snapshot is kept even if it doesn't fit on its own
+        let frames = self.config.seconds as usize * 60;
+        let max_len = frames.div_ceil(self.config.interval.max(1) as usize).max(1);
+        while self.used > self.config.memory || self.len() > max_len {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.used -= oldest.len();
+            self.recycle(oldest);
+        }
+    }
+
+    // the newest snapshot. It is dropped and the one before it takes its place, except for the
+    // oldest, which stays so that rewinding stops there
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.as_ref()?;
+        let Some(delta) = self.deltas.pop_back() else {
+            return Some(latest.clone());
+        };
+        let previous = apply_delta(latest, &delta, &mut self.xor);
+        self.used = self.used + previous.len() - latest.len() - delta.len();
+        self.recycle(delta);
+        self.latest.replace(previous)
+    }
+
+    fn recycle(&mut self, delta: Vec<u8>) {
+        if self.spare.len() < SPARE_DELTAS {
+            self.spare.push(delta);
+        }
+    }
+}
+
+// the older state XOR the newer one, compressed into delta after the lengths of the older state
+// and of the XOR
+fn compress_delta(
+    newer: &[u8],
+    mut older: Vec<u8>,
+    compressed: &mut Vec<u8>,
+    mut delta: Vec<u8>,
+) -> Vec<u8> {
+    let older_len = older.len();
+    older.resize(older_len.max(newer.len()), 0);
+    for (byte, newer) in older.iter_mut().zip(newer) {
+        *byte ^= newer;
+    }
+    compressed.resize(lz4_flex::block::get_maximum_output_size(older.len()), 0);
+    // the output has room for the worst case
+    let len = lz4_flex::compress_into(&older, compressed).unwrap();
+    delta.clear();
+    delta.extend_from_slice(&(older_len as u32).to_le_bytes());
+    delta.extend_from_slice(&(older.len() as u32).to_le_bytes());
+    delta.extend_from_slice(&compressed[..len]);
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8], xor: &mut Vec<u8>) -> Vec<u8> {
+    let older_len = u32::from_le_bytes(delta[0..4].try_into().unwrap()) as usize;
+    let xor_len = u32::from_le_bytes(delta[4..8].try_into().unwrap()) as usize;
+    xor.resize(xor_len, 0);
+    // only ever decompresses what compress_delta produced
+    lz4_flex::decompress_into(&delta[8..], xor).unwrap();
+    (0..older_len)
+        .map(|i| newer.get(i).copied().unwrap_or(0) ^ xor[i])
+        .collect()
+}
+
+enum Request {
+    Push(Vec<u8>),
+    StepBack(u32), // tagged with the generation its snapshot is for
+    Unused,        // the snapshot sent for the last step back won't be loaded
+}
+
+// requests waiting for the worker. While it is this far behind, new snapshots are dropped
+const QUEUE_LEN: usize = 4;
+
+// a RewindBuffer on its own thread, so that the CPU thread doesn't wait for snapshots to be
+// compressed or decoded. Requests are handled in order, a step back sees every snapshot pushed
+// before it. While rewinding, the snapshot for the next step back is decoded ahead of time
+pub struct Rewind {
+    requests: SyncSender<Request>,
+    states: Receiver<(u32, Option<Vec<u8>>)>,
+    config: RewindConfig,
+    asked: bool,     // a step back was sent and its snapshot hasn't been taken
+    generation: u32, // bumped whenever rewinding stops, snapshots for older ones are not wanted
+    unused: bool,    // an Unused didn't fit in the queue, it goes before any other request
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Self {
+        let (requests, recv) = mpsc::sync_channel(QUEUE_LEN);
+        // unbounded so that the worker never waits for snapshots nobody takes
+        let (reply, states) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = RewindBuffer::new(config);
+            // a copy of the last snapshot sent, to put back if it isn't used
+            let mut sent = None;
+            // ends once the Rewind is dropped
+            for request in recv {
+                match request {
+                    Request::Push(state) => {
+                        sent = None;
+                        buffer.push(state);
+                    }
+                    Request::StepBack(generation) => {
+                        let len = buffer.len();
+                        let state = buffer.step_back();
+                        // the oldest snapshot isn't taken out, so it isn't put back either
+                        sent = state.clone().filter(|_| buffer.len() < len);
+                        let _ = reply.send((generation, state));
+                    }
+                    Request::Unused => {
+                        if let Some(state) = sent.take() {
+                            buffer.push(state);
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            requests,
+            states,
+            config,
+            asked: false,
+            generation: 0,
+            unused: false,
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    // the snapshot is dropped if the worker is behind
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.send(Request::Push(state)) {
+            debug!("Rewind snapshot dropped, the worker is behind");
+        }
+    }
+
+    // asks for the newest snapshot, so that it is ready for the first step back
+    pub fn start(&mut self) {
+        self.ask();
+    }
+
+    // the snapshot decoded ahead goes back into the buffer
+    pub fn stop(&mut self) {
+        if self.asked {
+            self.asked = false;
+            self.generation = self.generation.wrapping_add(1);
+            self.unused = true;
+            self.send_unused();
+        }
+    }
+
+    // see RewindBuffer::step_back. None if the worker hasn't decoded the snapshot yet, the one
+    // before it is decoded while this one is used
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        if !self.asked {
+            self.ask();
+            return None;
+        }
+        let state = loop {
+            let (generation, state) = self.states.try_recv().ok()?;
+            if generation == self.generation {
+                break state;
+            }
+        };
+        self.asked = false;
+        self.ask();
+        state
+    }
+
+    fn ask(&mut self) {
+        if !self.asked {
+            self.asked = self.send(Request::StepBack(self.generation));
+        }
+    }
+
+    // false if the request doesn't fit in the queue
+    fn send(&mut self, request: Request) -> bool {
+        self.send_unused() && self.requests.try_send(request).is_ok()
+    }
+
+    fn send_unused(&mut self) -> bool {
+        if self.unused {
+            self.unused = self.requests.try_send(Request::Unused).is_err();
+        }
+        !self.unused
+    }
+}
@@ -0,0 +1,278 @@
+// save states. A header with the ROM checksum and a thumbnail is followed by tagged sections, one
+// per component. Readers skip sections they don't know and bytes at the end of a section they
+// don't read, so new fields go at the end of a section and older builds can still load the state
+
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"RGBS";
+// 2: the CPU stall is a u32
+pub const VERSION: u16 = 2;
+// the oldest version that can load states written by this one, raised for changes older readers
+// can't skip over
+const COMPATIBLE: u16 = 2;
+
+pub const THUMBNAIL_WIDTH: usize = 80;
+pub const THUMBNAIL_HEIGHT: usize = 72;
+
+// implemented by the components behind the bus, each writes its own section
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error>;
+}
+
+// the file a save state slot is kept in, next to the ROM
+pub fn slot_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+pub struct StateHeader {
+    pub version: u16,
+    pub rom_checksum: u32,
+    pub thumbnail: Vec<u8>, // THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT RGBA pixels
+}
+
+impl StateHeader {
+    // reads the header without loading the state, e.g. to show the thumbnail
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        Ok(StateReader::new(data)?.header)
+    }
+}
+
+pub fn rom_checksum(rom: &[u8]) -> u32 {
+    crc32fast::hash(rom)
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    rom_checksum: u32,
+    thumbnail: Vec<u8>,
+    data: Vec<u8>,
+    section: Option<usize>, // start of the section being written
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_rom_checksum(&mut self, checksum: u32) {
+        self.rom_checksum = checksum;
+    }
+
+    // keeps every other pixel of a 160x144 RGBA frame
+    pub fn set_thumbnail(&mut self, framebuffer: &[u8]) {
+        self.thumbnail = framebuffer
+            .chunks_exact(160 * 4)
+            .step_by(2)
+            .flat_map(|line| line.chunks_exact(4).step_by(2).flatten())
+            .copied()
+            .collect();
+    }
+
+    // starts a section, ending the one before it
+    pub fn section(&mut self, tag: &[u8; 4]) {
+        self.end_section();
+        self.data.extend_from_slice(tag);
+        self.section = Some(self.data.len());
+        self.data.extend_from_slice(&[0; 4]);
+    }
+
+    fn end_section(&mut self) {
+        if let Some(start) = self.section.take() {
+            let len = (self.data.len() - start - 4) as u32;
+            self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // with its length, so that readers can check it. Resizing a memory needs a new version
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.end_section();
+        let mut out = Vec::with_capacity(self.data.len() + self.thumbnail.len() + 24);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&COMPATIBLE.to_le_bytes());
+        out.extend_from_slice(&self.rom_checksum.to_le_bytes());
+        out.extend_from_slice(&(self.thumbnail.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.thumbnail);
+        out.extend_from_slice(&crc32fast::hash(&self.data).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        out
+    }
+}
+
+pub struct StateReader<'a> {
+    header: StateHeader,
+    sections: Vec<([u8; 4], &'a [u8])>,
+    tag: [u8; 4],
+    data: &'a [u8], // the rest of the current section
+}
+
+// the header and the list of sections, split off the front as they are read
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::InvalidSaveState("truncated".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl<'a> StateReader<'a> {
+    // checks the header and the data checksum, nothing is loaded if this fails
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor(data);
+        if cursor.take(4).ok() != Some(&MAGIC[..]) {
+            return Err(Error::InvalidSaveState("not a save state".to_string()));
+        }
+        let version = cursor.u16()?;
+        let compatible = cursor.u16()?;
+        if compatible > VERSION {
+            return Err(Error::SaveStateTooNew(version));
+        }
+        let rom_checksum = cursor.u32()?;
+        let len = cursor.u32()? as usize;
+        let thumbnail = cursor.take(len)?.to_vec();
+        let checksum = cursor.u32()?;
+        if crc32fast::hash(cursor.0) != checksum {
+            return Err(Error::InvalidSaveState("checksum mismatch".to_string()));
+        }
+
+        let mut sections = vec![];
+        while !cursor.0.is_empty() {
+            let tag = cursor.take(4)?.try_into().unwrap();
+            let len = cursor.u32()? as usize;
+            sections.push((tag, cursor.take(len)?));
+        }
+        Ok(Self {
+            header: StateHeader {
+                version,
+                rom_checksum,
+                thumbnail,
+            },
+            sections,
+            tag: [0; 4],
+            data: &[],
+        })
+    }
+
+    pub fn header(&self) -> &StateHeader {
+        &self.header
+    }
+
+    // the version the state was written with, fields added later are missing before it
+    pub fn version(&self) -> u16 {
+        self.header.version
+    }
+
+    // moves to the start of a section
+    pub fn section(&mut self, tag: &[u8; 4]) -> Result<(), Error> {
+        let Some(&(_, data)) = self.sections.iter().find(|(t, _)| t == tag) else {
+            return Err(Error::InvalidSaveState(format!(
+                "missing {} section",
+                String::from_utf8_lossy(tag).trim_end()
+            )));
+        };
+        self.tag = *tag;
+        self.data = data;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(Error::InvalidSaveState(format!(
+                "{} section is too short",
+                String::from_utf8_lossy(&self.tag).trim_end()
+            )));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // fills a memory of a fixed size. Every version so far writes the same sizes, a memory
+    // resized in a later one is checked against the size for the state's version()
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let data = self.bytes()?;
+        if data.len() != out.len() {
+            return Err(Error::InvalidSaveState(format!(
+                "expected {} bytes in the {} section, found {}",
+                out.len(),
+                String::from_utf8_lossy(&self.tag).trim_end(),
+                data.len()
+            )));
+        }
+        out.copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
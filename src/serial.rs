@@ -1,3 +1,5 @@
+use crate::error::Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
 use log::debug;
 
 #[derive(Default)]
@@ -25,3 +27,18 @@ impl Serial {
         }
     }
 }
+
+impl Snapshot for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        state.section(b"SERL");
+        state.u8(self.data);
+        state.u8(self.control);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.section(b"SERL")?;
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        Ok(())
+    }
+}
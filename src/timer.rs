@@ -1,13 +1,22 @@
 use log::debug;
+use crate::error::Error;
 use crate::memory::Interrupt;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
+// TIMA is incremented on the falling edge of the selected system counter bit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TimaState {
+    Running,
+    Overflowed, // TIMA reads 0x00 for one machine cycle, a write to TIMA cancels the reload
+    Reloading,  // TMA is copied into TIMA, writes to TIMA are ignored, writes to TMA go through to TIMA
+}
 
 pub struct Timer {
-    div: u8,
+    counter: u16, // internal system counter, DIV is the upper 8 bits
     tima: u8,
     tma: u8,
     tac: u8,
-    div_countdown: u16,
-    timer_countdown: i32,
+    state: TimaState,
 }
 
 impl Default for Timer {
@@ -19,42 +28,51 @@ impl Default for Timer {
 impl Timer {
     pub fn new() -> Self {
         Self {
-            div: 0,
+            counter: 0xABCC, // value after the DMG boot ROM
             tima: 0,
             tma: 0,
             tac: 0,
-            div_countdown: 64 * 4,
-            timer_countdown: 0,
+            state: TimaState::Running,
         }
     }
 
+    // the system counter bit selected by TAC, AND-ed with the timer enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
+            _ => unreachable!(),
+        };
+        self.tac & 0b100 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.state = TimaState::Overflowed;
+        }
+    }
+
+    // one machine cycle
     pub fn cycle(&mut self) -> Option<Interrupt> {
         let mut interrupt = None;
-        if self.div_countdown == 0 {
-            self.div_countdown = 64 * 4;
-            self.div = self.div.wrapping_add(1);
-        } else {
-            self.div_countdown -= 1;
-        }
-        let timer_enabled = self.tac & 0b100 == 0b100;
-        if self.timer_countdown == 0 && timer_enabled {
-            // if timer is enabled
-            self.tima = self.tima.wrapping_add(1);
-            if self.tima == 0 {
+        match self.state {
+            TimaState::Overflowed => {
                 self.tima = self.tma;
+                self.state = TimaState::Reloading;
                 interrupt = Some(Interrupt::Timer);
             }
-            let duration = match self.tac & 0b11 {
-                0b00 => 256,
-                0b01 => 4,
-                0b10 => 16,
-                0b11 => 64,
-                _ => unreachable!(),
-            };
-            self.timer_countdown = duration * 4;
+            TimaState::Reloading => self.state = TimaState::Running,
+            TimaState::Running => {}
         }
-        if timer_enabled {
-            self.timer_countdown -= 1;
+
+        let old_signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if old_signal && !self.signal() {
+            self.increment_tima();
         }
         interrupt
     }
@@ -62,22 +80,68 @@ impl Timer {
     pub fn read(&self, addr: u16) -> u8 {
         debug!("Timer read: {:#X}", addr);
         match addr {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0xF8,
             _ => unreachable!(),
         }
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
         debug!("Timer write: {:#X} {:#X}", addr, value);
+        let old_signal = self.signal();
         match addr {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
-            0xFF07 => self.tac = value,
+            0xFF04 => self.counter = 0,
+            0xFF05 => match self.state {
+                TimaState::Overflowed => {
+                    self.tima = value;
+                    self.state = TimaState::Running;
+                }
+                TimaState::Reloading => {}
+                TimaState::Running => self.tima = value,
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.state == TimaState::Reloading {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => self.tac = value & 0b111,
             _ => unreachable!(),
         }
+        // resetting DIV or changing TAC can produce a falling edge too
+        if old_signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
+}
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.section(b"TIMR");
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.u8(match self.state {
+            TimaState::Running => 0,
+            TimaState::Overflowed => 1,
+            TimaState::Reloading => 2,
+        });
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.section(b"TIMR")?;
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()? & 0b111;
+        self.state = match state.u8()? {
+            1 => TimaState::Overflowed,
+            2 => TimaState::Reloading,
+            _ => TimaState::Running,
+        };
+        Ok(())
     }
 }
@@ -1,12 +1,29 @@
 use eframe::egui::util::History;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use eframe::egui::{Color32, Context, TextureHandle, TextureOptions};
 use std::collections::HashSet;
 use eframe::{egui, Frame};
 use log::info;
-use crate::{ControlMsg, FrameData};
+use crate::ControlMsg;
+use crate::apu::{AudioChannel, Oscilloscope};
+use crate::cpu::Fault;
+use crate::frame::FrameReader;
 use crate::joypad::JoypadKey;
+use crate::ppu::Rgba;
+
+impl From<Rgba> for Color32 {
+    fn from(pixel: Rgba) -> Self {
+        Color32::from_rgba_unmultiplied(pixel.r, pixel.g, pixel.b, pixel.a)
+    }
+}
+
+fn color_image(pixels: &[Rgba]) -> egui::ColorImage {
+    egui::ColorImage {
+        size: [160, 144],
+        pixels: pixels.iter().map(|&pixel| pixel.into()).collect(),
+    }
+}
 
 pub struct FrameHistory {
     frame_times: History<f32>,
@@ -41,41 +58,112 @@ impl FrameHistory {
     }
 }
 
+// frames per second the emulation produces, measured over about a second
+struct EmulatedFps {
+    frames: u64,
+    since: f64,
+    fps: f32,
+}
+
+impl EmulatedFps {
+    fn update(&mut self, now: f64, frames: u64) {
+        if now - self.since >= 1.0 {
+            self.fps = (frames - self.frames) as f32 / (now - self.since) as f32;
+            self.frames = frames;
+            self.since = now;
+        }
+    }
+}
+
 pub struct App {
-    frame_history: FrameHistory,
-    recv_from_cpu: Receiver<FrameData>,
+    frame_history: FrameHistory, // frames presented by the UI
+    emulated_fps: EmulatedFps,
     send_to_cpu: Sender<ControlMsg>,
     texture: Option<TextureHandle>,
     debug_texture: Option<TextureHandle>,
-    framebuffer: Arc<Mutex<Vec<Color32>>>,
-    framebuffer_dirty: Arc<Mutex<bool>>,
+    frames: FrameReader,
     keys: HashSet<egui::Key>,
-    debug_framebuffer: Arc<Mutex<Vec<Color32>>>,
-    debug_framebuffer_dirty: Arc<Mutex<bool>>,
+    debug_frames: FrameReader,
+    oscilloscope: Arc<Mutex<Oscilloscope>>,
+    muted: [bool; 4],
+    solo: [bool; 4],
+    fault: Arc<Mutex<Option<Fault>>>,
+    break_on_illegal: bool,
+    blank_when_off: bool,
 }
 
 impl App {
     pub fn new(
-        recv_from_cpu: Receiver<FrameData>,
         send_to_cpu: Sender<ControlMsg>,
-        framebuffer: Arc<Mutex<Vec<Color32>>>,
-        debug_framebuffer: Arc<Mutex<Vec<Color32>>>,
-        framebuffer_dirty: Arc<Mutex<bool>>,
-        debug_framebuffer_dirty: Arc<Mutex<bool>>,
+        frames: FrameReader,
+        debug_frames: FrameReader,
+        oscilloscope: Arc<Mutex<Oscilloscope>>,
+        fault: Arc<Mutex<Option<Fault>>>,
     ) -> Self {
         Self {
             frame_history: FrameHistory::default(),
-            recv_from_cpu,
+            emulated_fps: EmulatedFps {
+                frames: 0,
+                since: 0.0,
+                fps: 0.0,
+            },
             send_to_cpu,
             texture: None,
             debug_texture: None,
-            framebuffer,
-            debug_framebuffer,
-            framebuffer_dirty,
-            debug_framebuffer_dirty,
+            frames,
+            debug_frames,
             keys: HashSet::new(),
+            oscilloscope,
+            muted: [false; 4],
+            solo: [false; 4],
+            fault,
+            break_on_illegal: false,
+            blank_when_off: false,
         }
     }
+
+    fn audio_panel(&mut self, ui: &mut egui::Ui) {
+        let oscilloscope = self.oscilloscope.lock().unwrap();
+        for channel in AudioChannel::ALL {
+            let index = channel.index();
+            ui.horizontal(|ui| {
+                ui.label(channel.name());
+                if ui.checkbox(&mut self.muted[index], "Mute").changed() {
+                    self.send_to_cpu
+                        .send(ControlMsg::MuteChannel(channel, self.muted[index]))
+                        .unwrap();
+                }
+                if ui.checkbox(&mut self.solo[index], "Solo").changed() {
+                    self.send_to_cpu
+                        .send(ControlMsg::SoloChannel(channel, self.solo[index]))
+                        .unwrap();
+                }
+            });
+            draw_scope(ui, &oscilloscope.channels[index]);
+        }
+        ui.label("Mixed");
+        draw_scope(ui, &oscilloscope.mixed);
+    }
+}
+
+// draws samples in the range -1.0..=1.0 as a line across the available width
+fn draw_scope(ui: &mut egui::Ui, samples: &[f32]) {
+    let size = egui::vec2(ui.available_width(), 32.0);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::BLACK);
+    let step = rect.width() / (samples.len() - 1) as f32;
+    let points = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            egui::pos2(
+                rect.left() + i as f32 * step,
+                rect.center().y - sample.clamp(-1.0, 1.0) * rect.height() / 2.0,
+            )
+        })
+        .collect::<Vec<_>>();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, Color32::GREEN)));
 }
 
 impl eframe::App for App {
@@ -164,27 +252,46 @@ impl eframe::App for App {
                     .send(ControlMsg::KeyUp(JoypadKey::Select))
                     .unwrap();
             }
+            // rewinds while held
+            if new_keys.contains(&egui::Key::Backspace) {
+                self.send_to_cpu.send(ControlMsg::Rewind(true)).unwrap();
+            }
+            if released_keys.contains(&egui::Key::Backspace) {
+                self.send_to_cpu.send(ControlMsg::Rewind(false)).unwrap();
+            }
             self.keys = keys.clone();
+
+            // F1-F4 load a save state slot, with shift they save to it
+            let slots = [egui::Key::F1, egui::Key::F2, egui::Key::F3, egui::Key::F4];
+            for (slot, key) in (1..).zip(slots) {
+                if i.key_pressed(key) {
+                    let msg = if i.modifiers.shift {
+                        ControlMsg::SaveState(slot)
+                    } else {
+                        ControlMsg::LoadState(slot)
+                    };
+                    self.send_to_cpu.send(msg).unwrap();
+                }
+            }
         });
-        if *self.framebuffer_dirty.lock().unwrap() {
-            self.frame_history
-                .on_new_frame(ctx.input(|i| i.time), frame.info().cpu_usage);
-            let img = egui::ColorImage {
-                size: [160, 144],
-                pixels: self.framebuffer.lock().unwrap().clone(),
-            };
+        let now = ctx.input(|i| i.time);
+        self.emulated_fps.update(now, self.frames.published());
+        if let Some(pixels) = self.frames.latest() {
+            self.frame_history.on_new_frame(now, frame.info().cpu_usage);
+            let img = color_image(pixels);
             self.texture = Some(ctx.load_texture("framebuffer", img, TextureOptions::NEAREST));
         }
-        if *self.debug_framebuffer_dirty.lock().unwrap() {
-            let img = egui::ColorImage {
-                size: [160, 144],
-                pixels: self.debug_framebuffer.lock().unwrap().clone(),
-            };
+        if let Some(pixels) = self.debug_frames.latest() {
+            let img = color_image(pixels);
             self.debug_texture = Some(ctx.load_texture("debug_framebuffer", img, TextureOptions::NEAREST));
         }
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
-                ui.label(format!("FPS: {:.1}", self.frame_history.fps()));
+                ui.label(format!(
+                    "FPS: {:.1} emulated, {:.1} presented",
+                    self.emulated_fps.fps,
+                    self.frame_history.fps()
+                ));
 
                 if ui.button("Debug").clicked() {
                     info!("Sending debug message to CPU");
@@ -195,18 +302,43 @@ impl eframe::App for App {
                     info!("Sending reset message to CPU");
                     self.send_to_cpu.send(ControlMsg::Reset).unwrap();
                 }
+
+                if ui.checkbox(&mut self.break_on_illegal, "Break on illegal opcode").changed() {
+                    self.send_to_cpu
+                        .send(ControlMsg::BreakOnIllegal(self.break_on_illegal))
+                        .unwrap();
+                }
+
+                if ui.checkbox(&mut self.blank_when_off, "Blank when LCD is off").changed() {
+                    self.send_to_cpu
+                        .send(ControlMsg::BlankWhenLcdOff(self.blank_when_off))
+                        .unwrap();
+                }
             });
+            if let Some(fault) = &*self.fault.lock().unwrap() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::RED, fault.to_string());
+                    if self.break_on_illegal && ui.button("Continue").clicked() {
+                        self.send_to_cpu.send(ControlMsg::Continue).unwrap();
+                    }
+                });
+            }
             if let Some(texture) = &self.texture {
-                let img = egui::Image::new(texture).fit_to_exact_size(ui.available_size());
+                let size = egui::vec2(ui.available_width(), ui.available_height() * 0.6);
+                let img = egui::Image::new(texture).fit_to_exact_size(size);
                 ui.add(img);
             }
             ui.separator();
-            ui.label("VRAM");
-            if let Some(debug_texture) = &self.debug_texture {
-                let img = egui::Image::new(debug_texture).fit_to_exact_size(ui.available_size());
-                ui.add(img);
-            }
-            
+            ui.columns(2, |columns| {
+                columns[0].label("VRAM");
+                if let Some(debug_texture) = &self.debug_texture {
+                    let size = columns[0].available_size();
+                    let img = egui::Image::new(debug_texture).fit_to_exact_size(size);
+                    columns[0].add(img);
+                }
+                columns[1].label("Audio");
+                self.audio_panel(&mut columns[1]);
+            });
         });
         ctx.request_repaint();
     }
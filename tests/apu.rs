@@ -0,0 +1,76 @@
+use rustgb::apu::{Apu, AudioChannel};
+use std::sync::{Arc, Mutex};
+
+fn apu() -> Apu {
+    Apu::new(Arc::new(Mutex::new(Default::default())))
+}
+
+// square 1 at full volume on both sides, every other channel is off
+fn playing_apu() -> Apu {
+    let mut apu = apu();
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0x11);
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x87);
+    apu
+}
+
+fn audible(apu: &Apu) -> [bool; 4] {
+    AudioChannel::ALL.map(|channel| apu.is_audible(channel))
+}
+
+// the mixed output in the oscilloscope, run long enough that all of it comes after the last change
+fn mixed(apu: &mut Apu) -> Vec<f32> {
+    for _ in 0..25_000 {
+        apu.cycle();
+    }
+    apu.oscilloscope.lock().unwrap().mixed.clone()
+}
+
+#[test]
+fn solo_overrides_the_others_and_mute_beats_solo() {
+    let mut apu = apu();
+    assert_eq!(audible(&apu), [true; 4]);
+
+    apu.set_solo(AudioChannel::Wave, true);
+    apu.set_solo(AudioChannel::Noise, true);
+    assert_eq!(audible(&apu), [false, false, true, true]);
+
+    apu.set_muted(AudioChannel::Wave, true);
+    apu.set_muted(AudioChannel::Square1, true);
+    assert_eq!(audible(&apu), [false, false, false, true]);
+
+    apu.set_solo(AudioChannel::Wave, false);
+    apu.set_solo(AudioChannel::Noise, false);
+    assert_eq!(audible(&apu), [false, true, false, true]);
+}
+
+#[test]
+fn silenced_channels_are_left_out_of_the_mix() {
+    let silent = |samples: &[f32]| samples.iter().all(|&sample| sample == 0.0);
+
+    let mut apu = playing_apu();
+    assert!(mixed(&mut apu).iter().all(|&sample| sample != 0.0));
+
+    apu.set_muted(AudioChannel::Square1, true);
+    assert!(silent(&mixed(&mut apu)));
+
+    // soloing another channel silences it too, soloing it brings it back
+    apu.set_muted(AudioChannel::Square1, false);
+    apu.set_solo(AudioChannel::Square2, true);
+    assert!(silent(&mixed(&mut apu)));
+    apu.set_solo(AudioChannel::Square1, true);
+    assert!(!silent(&mixed(&mut apu)));
+
+    // muting only changes the mix, the channel still runs out of length like it would
+    let mut muted = playing_apu();
+    muted.set_muted(AudioChannel::Square1, true);
+    muted.write(0xFF11, 0xBF);
+    muted.write(0xFF14, 0xC7);
+    assert_eq!(muted.read(0xFF26) & 0x01, 0x01);
+    mixed(&mut muted);
+    assert_eq!(muted.read(0xFF26) & 0x01, 0x00);
+}
@@ -0,0 +1,241 @@
+use common::rom_memory;
+use rustgb::cpu::{Cpu, Engine};
+use rustgb::memory::{Interrupt, LinearMemory, Memory, RegisterPairValue};
+use rustgb::Register;
+use std::sync::mpsc;
+
+mod common;
+
+// linear memory with switchable banks at 0x4000-0x7FFF, selected by writing to 0x2000, and
+// WRAM banks at 0xD000-0xDFFF selected through SVBK
+struct BankedMemory {
+    mem: LinearMemory<{ 64 * 1024 }>,
+    banks: Vec<Vec<u8>>,
+    bank: usize,
+    wram: Vec<Vec<u8>>,
+    wram_bank: usize,
+}
+
+impl Memory for BankedMemory {
+    fn get(&self, addr: u16) -> u8 {
+        match addr {
+            0x4000..=0x7FFF => self.banks[self.bank][addr as usize - 0x4000],
+            0xD000..=0xDFFF => self.wram[self.wram_bank][addr as usize - 0xD000],
+            _ => self.mem.get(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x2000..=0x3FFF => self.bank = value as usize,
+            0xD000..=0xDFFF => self.wram[self.wram_bank][addr as usize - 0xD000] = value,
+            0xFF70 => self.wram_bank = (value as usize & 0x07).max(1),
+            _ => self.mem.write(addr, value),
+        }
+    }
+
+    fn update<F>(&mut self, addr: u16, closure: F)
+    where
+        F: FnOnce() -> u8,
+    {
+        let result = closure();
+        self.write(addr, result);
+    }
+
+    fn cycle(&mut self) {}
+
+    fn enable_interrupt(&mut self, interrupt: Interrupt, enable: bool) {
+        self.mem.enable_interrupt(interrupt, enable);
+    }
+
+    fn enabled_interrupts(&self) -> u8 {
+        self.mem.enabled_interrupts()
+    }
+
+    fn request_interrupt(&mut self, interrupt: u8) {
+        self.mem.request_interrupt(interrupt);
+    }
+
+    fn requested_interrupts(&self) -> u8 {
+        self.mem.requested_interrupts()
+    }
+
+    fn set_requested_interrupts(&mut self, value: u8) {
+        self.mem.set_requested_interrupts(value);
+    }
+
+    fn clear_requested_interrupt(&mut self, interrupt: Interrupt) {
+        self.mem.clear_requested_interrupt(interrupt);
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 {
+            0
+        } else {
+            self.bank
+        }
+    }
+}
+
+fn banked_cpu(program: &[u8], banks: Vec<Vec<u8>>) -> Cpu<BankedMemory> {
+    let (_send, recv) = mpsc::channel();
+    let mut mem = BankedMemory {
+        mem: LinearMemory::new(),
+        banks,
+        bank: 1,
+        wram: vec![vec![0; 0x1000]; 8],
+        wram_bank: 1,
+    };
+    for (i, byte) in program.iter().enumerate() {
+        mem.write(0x0100 + i as u16, *byte);
+    }
+    let mut cpu = Cpu::new(mem, recv);
+    cpu.sp = RegisterPairValue::from(0xD000);
+    cpu
+}
+
+fn registers<M: Memory>(cpu: &Cpu<M>) -> Vec<u8> {
+    let mut registers = [
+        Register::A,
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::H,
+        Register::L,
+    ]
+    .map(|register| cpu.register(register))
+    .to_vec();
+    registers.extend(cpu.pc.as_u16().to_be_bytes());
+    registers.extend(cpu.sp.as_u16().to_be_bytes());
+    registers
+}
+
+// runs the same program with both engines and checks that they end up in the same state
+fn run_both(program: &[u8], banks: Vec<Vec<u8>>, cycles: usize) -> Cpu<BankedMemory> {
+    let mut interpreter = banked_cpu(program, banks.clone());
+    let mut cached = banked_cpu(program, banks);
+    cached.set_engine(Engine::BlockCache);
+    for cycle in 0..cycles {
+        interpreter.cycle();
+        cached.cycle();
+        assert_eq!(
+            registers(&interpreter),
+            registers(&cached),
+            "cycle {}",
+            cycle
+        );
+    }
+    for addr in 0xC000..=0xDFFF {
+        assert_eq!(interpreter.mem.get(addr), cached.mem.get(addr));
+    }
+    cached
+}
+
+#[test]
+fn self_modifying_code_in_wram() {
+    #[rustfmt::skip]
+    let program = [
+        0x21, 0x00, 0xC1, // LD HL, 0xC100
+        0x36, 0x14,       // LD [HL], INC D
+        0x23,             // INC HL
+        0x36, 0xC9,       // LD [HL], RET
+        0x2B,             // DEC HL
+        0x06, 0x20,       // LD B, 0x20
+        0xCD, 0x00, 0xC1, // loop: CALL 0xC100
+        0x7E,             // LD A, [HL]
+        0xEE, 0x18,       // XOR INC D ^ INC C
+        0x77,             // LD [HL], A
+        0x05,             // DEC B
+        0x20, 0xF6,       // JR NZ, loop
+        0x18, 0xFE,       // JR -2
+    ];
+    let cpu = run_both(&program, vec![vec![0; 0x4000]; 2], 2000);
+    assert_eq!(cpu.register(Register::B), 0);
+    assert_eq!(cpu.register(Register::D), 0x10);
+    assert_eq!(cpu.register(Register::C), 0x13 + 0x10);
+}
+
+#[test]
+fn bank_switched_code() {
+    #[rustfmt::skip]
+    let program = [
+        0x06, 0x10,       // LD B, 0x10
+        0x3E, 0x01,       // loop: LD A, 1
+        0xEA, 0x00, 0x20, // LD [0x2000], A
+        0xCD, 0x00, 0x40, // CALL 0x4000
+        0x3E, 0x02,       // LD A, 2
+        0xEA, 0x00, 0x20, // LD [0x2000], A
+        0xCD, 0x00, 0x40, // CALL 0x4000
+        0x05,             // DEC B
+        0x20, 0xED,       // JR NZ, loop
+        0x18, 0xFE,       // JR -2
+    ];
+    let mut banks = vec![vec![0; 0x4000]; 3];
+    banks[1][..2].copy_from_slice(&[0x14, 0xC9]); // INC D; RET
+    banks[2][..2].copy_from_slice(&[0x1C, 0xC9]); // INC E; RET
+    let cpu = run_both(&program, banks, 2000);
+    assert_eq!(cpu.register(Register::B), 0);
+    assert_eq!(cpu.register(Register::D), 0x10);
+    assert_eq!(cpu.register(Register::E), 0xD8 + 0x10);
+}
+
+#[test]
+fn wram_bank_switched_code() {
+    #[rustfmt::skip]
+    let program = [
+        0x21, 0x00, 0xD0, // LD HL, 0xD000
+        0x3E, 0x01,       // LD A, 1
+        0xE0, 0x70,       // LDH [SVBK], A
+        0x36, 0x14,       // LD [HL], INC D
+        0x23,             // INC HL
+        0x36, 0xC9,       // LD [HL], RET
+        0x3E, 0x02,       // LD A, 2
+        0xE0, 0x70,       // LDH [SVBK], A
+        0x36, 0xC9,       // LD [HL], RET
+        0x2B,             // DEC HL
+        0x36, 0x1C,       // LD [HL], INC E
+        0x06, 0x10,       // LD B, 0x10
+        0x3E, 0x01,       // loop: LD A, 1
+        0xE0, 0x70,       // LDH [SVBK], A
+        0xCD, 0x00, 0xD0, // CALL 0xD000
+        0x3E, 0x02,       // LD A, 2
+        0xE0, 0x70,       // LDH [SVBK], A
+        0xCD, 0x00, 0xD0, // CALL 0xD000
+        0x05,             // DEC B
+        0x20, 0xEF,       // JR NZ, loop
+        0x18, 0xFE,       // JR -2
+    ];
+    let cpu = run_both(&program, vec![vec![0; 0x4000]; 2], 2000);
+    assert_eq!(cpu.register(Register::B), 0);
+    assert_eq!(cpu.register(Register::D), 0x10);
+    assert_eq!(cpu.register(Register::E), 0xD8 + 0x10);
+}
+
+#[test]
+fn dma_starting_during_an_instruction() {
+    let mut rom = vec![0; 0x8000];
+    #[rustfmt::skip]
+    rom[0x100..0x106].copy_from_slice(&[
+        0x3E, 0xC1, // LD A, 0xC1
+        0xE0, 0x46, // LDH [DMA], A
+        0x06, 0x55, // LD B, 0x55, the transfer takes over the bus after the opcode
+    ]);
+    let (_send, recv) = mpsc::channel();
+    let mut interpreter = Cpu::new(rom_memory(rom.clone()), recv);
+    let (_send, recv) = mpsc::channel();
+    let mut cached = Cpu::new(rom_memory(rom), recv);
+    cached.set_engine(Engine::BlockCache);
+    // the CPU runs the NOPs the transfer reads from WRAM until it ends
+    for cycle in 0..200 {
+        interpreter.cycle();
+        cached.cycle();
+        assert_eq!(
+            registers(&interpreter),
+            registers(&cached),
+            "cycle {}",
+            cycle
+        );
+    }
+    assert_eq!(cached.register(Register::B), 0x00);
+}
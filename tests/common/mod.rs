@@ -1 +1,39 @@
+// shared by the integration tests, each of them only uses some of it
+#![allow(dead_code)]
+
+use rustgb::apu::Apu;
+use rustgb::frame::triple_buffer;
+use rustgb::memory::{MappedMemory, Mbc, RomOnlyMbc};
+use rustgb::ppu::Ppu;
+use rustgb::timer::Timer;
+use rustgb::Model;
+use std::sync::{Arc, Mutex};
+
 pub mod util;
+
+// the whole bus with an empty ROM-only cartridge, frames and samples go nowhere
+pub fn mapped_memory(model: Model) -> MappedMemory<RomOnlyMbc> {
+    let mut mem = rom_memory(vec![0; 0x8000]);
+    mem.model = model;
+    mem
+}
+
+// the same with a ROM in the cartridge
+pub fn rom_memory(rom: Vec<u8>) -> MappedMemory<RomOnlyMbc> {
+    let ppu = Ppu::new(triple_buffer().0, triple_buffer().0);
+    let apu = Apu::new(Arc::new(Mutex::new(Default::default())));
+    MappedMemory::new(RomOnlyMbc::new(rom), ppu, Timer::new(), apu)
+}
+
+// a ROM-only cartridge that keeps counting in A and scrolls the background with it
+pub fn counting_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // NOP; JP 0x0150
+    rom[0x150..0x159].copy_from_slice(&[
+        0x3C, // INC A
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+        0xE0, 0x43, // LDH (0x43), A
+        0xC3, 0x50, 0x01, // JP 0x0150
+    ]);
+    rom
+}
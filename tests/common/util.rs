@@ -32,16 +32,16 @@ pub fn disassemble_byte(byte: u8) -> Instruction {
 
         (0, 0, 1, 1, 0, 1, 0, 0) => Instruction::Arithmetic(ArithmeticInstruction::IncMemHL),
         (0, 0, a, b, c, 1, 0, 0) => {
-            Instruction::Arithmetic(ArithmeticInstruction::IncR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::IncR8(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 1, 1, 0, 1, 0, 1) => Instruction::Arithmetic(ArithmeticInstruction::DecMemHL),
         (0, 0, a, b, c, 1, 0, 1) => {
-            Instruction::Arithmetic(ArithmeticInstruction::DecR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::DecR8(Register::from_bits(a, b, c).unwrap()))
         }
 
         (0, 0, 1, 1, 0, 1, 1, 0) => Instruction::Load(LoadInstruction::LdMemHLN8(0)),
         (0, 0, a, b, c, 1, 1, 0) => {
-            Instruction::Load(LoadInstruction::LdR8N8(Register::from_bits(a, b, c), 0))
+            Instruction::Load(LoadInstruction::LdR8N8(Register::from_bits(a, b, c).unwrap(), 0))
         }
 
         (0, 0, 0, 0, 0, 1, 1, 1) => Instruction::Bit(BitInstruction::Rlca),
@@ -64,48 +64,48 @@ pub fn disassemble_byte(byte: u8) -> Instruction {
         (0, 1, 1, 1, 0, 1, 1, 0) => Instruction::Misc(MiscInstruction::Halt),
 
         (0, 1, 1, 1, 0, a, b, c) => {
-            Instruction::Load(LoadInstruction::LdMemHLR8(Register::from_bits(a, b, c)))
+            Instruction::Load(LoadInstruction::LdMemHLR8(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 1, a, b, c, 1, 1, 0) => {
-            Instruction::Load(LoadInstruction::LdR8MemHL(Register::from_bits(a, b, c)))
+            Instruction::Load(LoadInstruction::LdR8MemHL(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 1, a, b, c, x, y, z) => Instruction::Load(LoadInstruction::LdR8R8(
-            Register::from_bits(a, b, c),
-            Register::from_bits(x, y, z),
+            Register::from_bits(a, b, c).unwrap(),
+            Register::from_bits(x, y, z).unwrap(),
         )),
 
         // Block 2
         (1, 0, 0, 0, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AddAMemHL),
         (1, 0, 0, 0, 0, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::AddAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::AddAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 0, 0, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AdcAMemHL),
         (1, 0, 0, 0, 1, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::AdcAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::AdcAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 0, 1, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::SubAMemHL),
         (1, 0, 0, 1, 0, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::SubAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::SubAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 0, 1, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::SbcAMemHL),
         (1, 0, 0, 1, 1, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::SbcAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::SbcAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 1, 0, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::AndAMemHL),
         (1, 0, 1, 0, 0, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::AndAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::AndAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 1, 0, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::XorAMemHL),
         (1, 0, 1, 0, 1, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::XorAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::XorAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 1, 1, 0, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::OrAMemHL),
         (1, 0, 1, 1, 0, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::OrAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::OrAR8(Register::from_bits(a, b, c).unwrap()))
         }
         (1, 0, 1, 1, 1, 1, 1, 0) => Instruction::Arithmetic(ArithmeticInstruction::CpAMemHL),
         (1, 0, 1, 1, 1, a, b, c) => {
-            Instruction::Arithmetic(ArithmeticInstruction::CpAR8(Register::from_bits(a, b, c)))
+            Instruction::Arithmetic(ArithmeticInstruction::CpAR8(Register::from_bits(a, b, c).unwrap()))
         }
 
         // Block 3
@@ -169,51 +169,51 @@ pub(crate) fn disassemble_prefix_byte(byte: u8) -> Instruction {
     match Disassembler::bits_tup(byte) {
         (0, 0, 0, 0, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::RlcMemHL),
         (0, 0, 0, 0, 0, a, b, c) => {
-            Instruction::Bit(BitInstruction::Rlc(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Rlc(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 0, 0, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::RrcMemHL),
         (0, 0, 0, 0, 1, a, b, c) => {
-            Instruction::Bit(BitInstruction::Rrc(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Rrc(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 0, 1, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::RlMemHL),
         (0, 0, 0, 1, 0, a, b, c) => {
-            Instruction::Bit(BitInstruction::Rl(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Rl(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 0, 1, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::RrMemHL),
         (0, 0, 0, 1, 1, a, b, c) => {
-            Instruction::Bit(BitInstruction::Rr(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Rr(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 1, 0, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::SlaMemHL),
         (0, 0, 1, 0, 0, a, b, c) => {
-            Instruction::Bit(BitInstruction::Sla(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Sla(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 1, 0, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::SraMemHL),
         (0, 0, 1, 0, 1, a, b, c) => {
-            Instruction::Bit(BitInstruction::Sra(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Sra(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 1, 1, 0, 1, 1, 0) => Instruction::Bit(BitInstruction::SwapMemHL),
         (0, 0, 1, 1, 0, a, b, c) => {
-            Instruction::Bit(BitInstruction::Swap(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Swap(Register::from_bits(a, b, c).unwrap()))
         }
         (0, 0, 1, 1, 1, 1, 1, 0) => Instruction::Bit(BitInstruction::SrlMemHL),
         (0, 0, 1, 1, 1, a, b, c) => {
-            Instruction::Bit(BitInstruction::Srl(Register::from_bits(a, b, c)))
+            Instruction::Bit(BitInstruction::Srl(Register::from_bits(a, b, c).unwrap()))
         }
 
         (0, 1, x, y, z, 1, 1, 0) => Instruction::Bit(BitInstruction::BitMemHL(x << 2 | y << 1 | z)),
         (0, 1, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Bit(
             x << 2 | y << 1 | z,
-            Register::from_bits(a, b, c),
+            Register::from_bits(a, b, c).unwrap(),
         )),
         (1, 0, x, y, z, 1, 1, 0) => Instruction::Bit(BitInstruction::ResMemHL(x << 2 | y << 1 | z)),
         (1, 0, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Res(
             x << 2 | y << 1 | z,
-            Register::from_bits(a, b, c),
+            Register::from_bits(a, b, c).unwrap(),
         )),
         (1, 1, x, y, z, 1, 1, 0) => Instruction::Bit(BitInstruction::SetMemHL(x << 2 | y << 1 | z)),
         (1, 1, x, y, z, a, b, c) => Instruction::Bit(BitInstruction::Set(
             x << 2 | y << 1 | z,
-            Register::from_bits(a, b, c),
+            Register::from_bits(a, b, c).unwrap(),
         )),
         x => panic!("Invalid prefix instruction: {:?}", x),
     }
@@ -1,141 +1,185 @@
 use rustgb::cpu::Cpu;
-use rustgb::memory::{LinearMemory, Mbc, Memory, RegisterPairValue};
+use rustgb::memory::{BusAccess, LinearMemory, Memory, RecordingMemory, RegisterPairValue};
 use rustgb::{Register, RegisterPair, RegisterPairStk};
+use serde_json::Value;
 use std::fs;
 use std::sync::mpsc;
 
 mod common;
 
-macro_rules! assert_eq_hex {
-    ($a:expr, $b:expr) => {
-        assert_eq!($a, $b, "0x{:02X} != 0x{:02X}", $a, $b)
+macro_rules! check_hex {
+    ($errors:expr, $what:expr, $a:expr, $b:expr) => {
+        if $a != $b {
+            $errors.push(format!("{}: 0x{:02X} != 0x{:02X}", $what, $a, $b));
+        }
     };
 }
 
-macro_rules! assert_eq_bin {
-    ($a:expr, $b:expr) => {
-        assert_eq!($a, $b, "0b{:08b} != 0b{:08b}", $a, $b)
+macro_rules! check_bin {
+    ($errors:expr, $what:expr, $a:expr, $b:expr) => {
+        if $a != $b {
+            $errors.push(format!("{}: 0b{:08b} != 0b{:08b}", $what, $a, $b));
+        }
     };
 }
 
-#[test]
-#[allow(clippy::explicit_counter_loop)]
-fn test() {
-    env_logger::init();
-    let test_dir = fs::read_dir("cpu-tests/v1/").unwrap();
+// results of all tests in one file, which covers a single opcode
+struct OpcodeReport {
+    opcode: String,
+    passed: usize,
+    total: usize,
+    first_failure: Option<String>,
+}
 
-    // open all files in dir and load with serde_json
-    let mut count = 0;
-    for dir_entry in test_dir {
-        let dir_entry = dir_entry.unwrap();
-        let file_path = dir_entry.path();
-        let file = fs::read_to_string(file_path).unwrap();
-        let tests: serde_json::Value = serde_json::from_str(&file).unwrap();
-        for (i, test) in tests.as_array().unwrap().iter().enumerate() {
-            println!("Total tests passed: {count}");
-            println!("=====================================================");
-            let name = test.get("name").unwrap().as_str().unwrap();
-            let instruction = if name.starts_with("CB") {
-                // parse 3rd and 4th character as hex
-                let hex = u8::from_str_radix(&name[3..5], 16).unwrap();
-                common::util::disassemble_prefix_byte(hex)
-            } else {
-                let hex = u8::from_str_radix(&name[0..2], 16).unwrap();
-                common::util::disassemble_byte(hex)
-            };
-            println!(
-                "Running [{i}] test '{}', which tests {:?}",
-                name, instruction
-            );
+fn byte(value: &Value) -> u8 {
+    value.as_u64().unwrap() as u8
+}
 
-            let (recv0, send0) = mpsc::channel();
-            let mem = LinearMemory::<{ 64 * 1024 }>::new();
-            let mut cpu = Cpu::new(mem, send0);
+fn word(value: &Value) -> u16 {
+    value.as_u64().unwrap() as u16
+}
 
-            let initial = test.get("initial").unwrap();
-            *cpu.register_mut(Register::A) = initial.get("a").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::B) = initial.get("b").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::C) = initial.get("c").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::D) = initial.get("d").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::E) = initial.get("e").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::H) = initial.get("h").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_mut(Register::L) = initial.get("l").unwrap().as_u64().unwrap() as u8;
-            *cpu.register_pair_stk_mut(RegisterPairStk::AF).low_mut() =
-                initial.get("f").unwrap().as_u64().unwrap() as u8;
-            cpu.pc = RegisterPairValue::from(initial.get("pc").unwrap().as_u64().unwrap() as u16);
-            *cpu.register_pair_mut(RegisterPair::SP) =
-                RegisterPairValue::from(initial.get("sp").unwrap().as_u64().unwrap() as u16);
-            println!(
-                "flags: 0x{:08b}; PC: 0x{:04X}; SP: 0x{:04X}",
-                cpu.register_pair_stk(RegisterPairStk::AF) as u8,
-                cpu.pc.as_u16(),
-                cpu.sp.as_u16()
-            );
-            for register in &[
-                Register::A,
-                Register::B,
-                Register::C,
-                Register::D,
-                Register::E,
-                Register::H,
-                Register::L,
-            ] {
-                print!("{:?}: 0x{:02X}  ", register, cpu.register(*register));
-            }
-            println!();
-            for ram_entry in initial.get("ram").unwrap().as_array().unwrap() {
-                let ram_entry = ram_entry.as_array().unwrap();
-                let addr = ram_entry[0].as_u64().unwrap() as u16;
-                let value = ram_entry[1].as_u64().unwrap() as u8;
-                println!("Writing 0x{:02X} to 0x{:04X}", value, addr);
-                cpu.mem.write(addr, value);
+// the bus accesses listed in the `cycles` array, one entry per machine cycle. Entries without
+// bus activity are null or have "---" as pins
+fn expected_accesses(cycles: &[Value]) -> Vec<BusAccess> {
+    cycles
+        .iter()
+        .enumerate()
+        .filter_map(|(cycle, entry)| {
+            let entry = entry.as_array()?;
+            let addr = word(&entry[0]);
+            let value = byte(&entry[1]);
+            match entry[2].as_str().unwrap().as_bytes() {
+                [b'r', ..] => Some(BusAccess::Read { cycle, addr, value }),
+                [_, b'w', ..] => Some(BusAccess::Write { cycle, addr, value }),
+                _ => None,
             }
+        })
+        .collect()
+}
 
-            let cycles = test.get("cycles").unwrap().as_array().unwrap();
+// runs a single test case and returns everything that didn't match
+fn run_test(test: &Value) -> Vec<String> {
+    let (_send, recv) = mpsc::channel();
+    let mem = RecordingMemory::new(LinearMemory::<{ 64 * 1024 }>::new());
+    let mut cpu = Cpu::new(mem, recv);
 
-            println!("Running {} cycles", cycles.len());
+    let initial = test.get("initial").unwrap();
+    *cpu.register_mut(Register::A) = byte(initial.get("a").unwrap());
+    *cpu.register_mut(Register::B) = byte(initial.get("b").unwrap());
+    *cpu.register_mut(Register::C) = byte(initial.get("c").unwrap());
+    *cpu.register_mut(Register::D) = byte(initial.get("d").unwrap());
+    *cpu.register_mut(Register::E) = byte(initial.get("e").unwrap());
+    *cpu.register_mut(Register::H) = byte(initial.get("h").unwrap());
+    *cpu.register_mut(Register::L) = byte(initial.get("l").unwrap());
+    *cpu.register_pair_stk_mut(RegisterPairStk::AF).low_mut() = byte(initial.get("f").unwrap());
+    cpu.pc = RegisterPairValue::from(word(initial.get("pc").unwrap()));
+    *cpu.register_pair_mut(RegisterPair::SP) =
+        RegisterPairValue::from(word(initial.get("sp").unwrap()));
+    for ram_entry in initial.get("ram").unwrap().as_array().unwrap() {
+        let ram_entry = ram_entry.as_array().unwrap();
+        cpu.mem.write(word(&ram_entry[0]), byte(&ram_entry[1]));
+    }
+    cpu.mem.take_log();
 
-            for cycle in cycles {
-                cpu.cycle();
-            }
+    let cycles = test.get("cycles").unwrap().as_array().unwrap();
+    let taken = cpu.step();
+
+    let mut errors = Vec::new();
+    if taken != cycles.len() {
+        errors.push(format!("took {} cycles instead of {}", taken, cycles.len()));
+    }
+    let log = cpu.mem.take_log();
+    let expected = expected_accesses(cycles);
+    if log != expected {
+        errors.push(format!("bus accesses {:?} != {:?}", log, expected));
+    }
 
-            for (key, value) in test.get("final").unwrap().as_object().unwrap() {
-                match key.as_str() {
-                    "a" => assert_eq_hex!(cpu.register(Register::A), value.as_u64().unwrap() as u8),
-                    "b" => assert_eq_hex!(cpu.register(Register::B), value.as_u64().unwrap() as u8),
-                    "c" => assert_eq_hex!(cpu.register(Register::C), value.as_u64().unwrap() as u8),
-                    "d" => assert_eq_hex!(cpu.register(Register::D), value.as_u64().unwrap() as u8),
-                    "e" => assert_eq_hex!(cpu.register(Register::E), value.as_u64().unwrap() as u8),
-                    "h" => assert_eq_hex!(cpu.register(Register::H), value.as_u64().unwrap() as u8),
-                    "l" => assert_eq_hex!(cpu.register(Register::L), value.as_u64().unwrap() as u8),
-                    "f" => assert_eq_bin!(
-                        cpu.register_pair_stk(RegisterPairStk::AF) as u8,
-                        value.as_u64().unwrap() as u8
-                    ),
-                    "pc" => assert_eq_hex!(cpu.pc.as_u16(), value.as_u64().unwrap() as u16),
-                    "sp" => assert_eq_hex!(
-                        cpu.register_pair(RegisterPair::SP),
-                        value.as_u64().unwrap() as u16
-                    ),
-                    "ram" => {
-                        for ram_entry in value.as_array().unwrap() {
-                            let ram_entry = ram_entry.as_array().unwrap();
-                            let addr = ram_entry[0].as_u64().unwrap() as u16;
-                            let value = ram_entry[1].as_u64().unwrap() as u8;
-                            let actual = cpu.mem.get(addr);
-                            if actual != value {
-                                println!(
-                                    "At addr {addr:04x}: Expected: 0x{:02X}, Actual: 0x{:02X}",
-                                    value, actual
-                                );
-                                assert_eq_hex!(actual, value);
-                            }
-                        }
-                    }
-                    _ => {}
+    for (key, value) in test.get("final").unwrap().as_object().unwrap() {
+        match key.as_str() {
+            "a" => check_hex!(errors, "a", cpu.register(Register::A), byte(value)),
+            "b" => check_hex!(errors, "b", cpu.register(Register::B), byte(value)),
+            "c" => check_hex!(errors, "c", cpu.register(Register::C), byte(value)),
+            "d" => check_hex!(errors, "d", cpu.register(Register::D), byte(value)),
+            "e" => check_hex!(errors, "e", cpu.register(Register::E), byte(value)),
+            "h" => check_hex!(errors, "h", cpu.register(Register::H), byte(value)),
+            "l" => check_hex!(errors, "l", cpu.register(Register::L), byte(value)),
+            "f" => check_bin!(
+                errors,
+                "f",
+                cpu.register_pair_stk(RegisterPairStk::AF) as u8,
+                byte(value)
+            ),
+            "pc" => check_hex!(errors, "pc", cpu.pc.as_u16(), word(value)),
+            "sp" => check_hex!(errors, "sp", cpu.register_pair(RegisterPair::SP), word(value)),
+            "ram" => {
+                for ram_entry in value.as_array().unwrap() {
+                    let ram_entry = ram_entry.as_array().unwrap();
+                    let addr = word(&ram_entry[0]);
+                    check_hex!(
+                        errors,
+                        format!("[{addr:04X}]"),
+                        cpu.mem.inner.get(addr),
+                        byte(&ram_entry[1])
+                    );
                 }
             }
-            count += 1;
+            _ => {}
+        }
+    }
+    errors
+}
+
+#[test]
+fn test() {
+    env_logger::init();
+    let mut files = fs::read_dir("cpu-tests/v1/")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let mut reports = Vec::new();
+    for file_path in files {
+        let file = fs::read_to_string(&file_path).unwrap();
+        let tests: Value = serde_json::from_str(&file).unwrap();
+        let tests = tests.as_array().unwrap();
+        let mut report = OpcodeReport {
+            opcode: file_path.file_stem().unwrap().to_string_lossy().into_owned(),
+            passed: 0,
+            total: tests.len(),
+            first_failure: None,
+        };
+        for test in tests {
+            let errors = run_test(test);
+            if errors.is_empty() {
+                report.passed += 1;
+            } else if report.first_failure.is_none() {
+                let name = test.get("name").unwrap().as_str().unwrap();
+                report.first_failure = Some(format!("'{}': {}", name, errors.join(", ")));
+            }
+        }
+        reports.push(report);
+    }
+
+    let mut failed = 0;
+    for report in &reports {
+        let instruction = if report.opcode.to_lowercase().starts_with("cb") {
+            u8::from_str_radix(&report.opcode[3..5], 16)
+                .map(|hex| format!("{:?}", common::util::disassemble_prefix_byte(hex)))
+        } else {
+            u8::from_str_radix(&report.opcode[0..2], 16)
+                .map(|hex| format!("{:?}", common::util::disassemble_byte(hex)))
+        }
+        .unwrap_or_default();
+        println!(
+            "{:<6} {:>5}/{:<5} {}",
+            report.opcode, report.passed, report.total, instruction
+        );
+        if let Some(failure) = &report.first_failure {
+            failed += 1;
+            println!("       first failure: {}", failure);
         }
     }
+    assert_eq!(failed, 0, "{} of {} opcodes failed", failed, reports.len());
 }
@@ -0,0 +1,22 @@
+use rustgb::disassembler::Disassembler;
+
+fn decode_both(bytes: [u8; 3]) -> (String, String) {
+    let mut table_bytes = bytes.into_iter();
+    let mut bits_bytes = bytes.into_iter();
+    (
+        format!("{:?}", Disassembler::decode(|| table_bytes.next().unwrap())),
+        format!("{:?}", Disassembler::decode_bits(|| bits_bytes.next().unwrap())),
+    )
+}
+
+#[test]
+fn table_matches_bit_decoding() {
+    for opcode in 0..=0xFFu8 {
+        let (table, bits) = decode_both([opcode, 0xA5, 0x5A]);
+        assert_eq!(table, bits, "opcode {:#04X}", opcode);
+    }
+    for opcode in 0..=0xFFu8 {
+        let (table, bits) = decode_both([0xCB, opcode, 0x00]);
+        assert_eq!(table, bits, "opcode 0xCB {:#04X}", opcode);
+    }
+}
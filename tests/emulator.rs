@@ -0,0 +1,67 @@
+use rustgb::apu::SAMPLE_RATE;
+use rustgb::error::Error;
+use rustgb::joypad::Buttons;
+use rustgb::Emulator;
+
+// a ROM-only cartridge that keeps copying the d-pad state to 0xC000
+fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // NOP; JP 0x0150
+    rom[0x150..0x15C].copy_from_slice(&[
+        0x3E, 0x20, // LD A, 0x20
+        0xE0, 0x00, // LDH (0x00), A
+        0xF0, 0x00, // LDH A, (0x00)
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+        0xC3, 0x50, 0x01, // JP 0x0150
+    ]);
+    rom
+}
+
+#[test]
+fn rejects_unsupported_cartridges() {
+    let mut rom = rom();
+    rom[0x147] = 0x01;
+    assert!(matches!(
+        Emulator::new(rom),
+        Err(Error::UnsupportedCartridge(_))
+    ));
+}
+
+#[test]
+fn steps_frames_and_instructions() {
+    let mut emulator = Emulator::new(rom()).unwrap();
+    assert_eq!(emulator.step_instruction(), 1);
+    assert_eq!(emulator.step_instruction(), 4);
+
+    // the LCD is on after boot, so frames end at VBlank
+    emulator.step_frame();
+    let cycles = emulator.step_frame();
+    assert!((17556..17556 + 6).contains(&cycles));
+    assert_eq!(emulator.framebuffer().len(), 160 * 144 * 4);
+    assert!(emulator.run_for_cycles(100) >= 100);
+}
+
+#[test]
+fn buttons_reach_the_game() {
+    let mut emulator = Emulator::new(rom()).unwrap();
+    emulator.set_buttons(Buttons::RIGHT | Buttons::A);
+    emulator.run_for_cycles(100);
+    assert_eq!(emulator.read(0xC000), 0xEE);
+    emulator.set_buttons(Buttons::empty());
+    emulator.run_for_cycles(100);
+    assert_eq!(emulator.read(0xC000), 0xEF);
+}
+
+#[test]
+fn audio_samples_are_collected_once_enabled() {
+    let mut emulator = Emulator::new(rom()).unwrap();
+    emulator.step_frame();
+    assert!(emulator.audio_samples().is_empty());
+
+    emulator.enable_audio();
+    let cycles = emulator.step_frame();
+    let expected = cycles as u64 * 4 * SAMPLE_RATE as u64 / 4_194_304;
+    let samples = emulator.audio_samples().len() as u64;
+    assert!(samples.abs_diff(expected) <= 1);
+    assert!(emulator.audio_samples().is_empty());
+}
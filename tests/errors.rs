@@ -0,0 +1,68 @@
+use rustgb::cpu::Cpu;
+use rustgb::error::Error;
+use rustgb::memory::{LinearMemory, Memory, RegisterPairValue};
+use rustgb::{CartridgeHeader, ControlMsg};
+use std::sync::mpsc;
+
+#[test]
+fn invalid_cartridge_type_is_an_error() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x04;
+    assert!(matches!(
+        CartridgeHeader::parse(&rom),
+        Err(Error::InvalidCartridgeType(0x04))
+    ));
+    assert!(matches!(
+        CartridgeHeader::parse(&rom[..0x100]),
+        Err(Error::RomTooSmall(0x100))
+    ));
+}
+
+#[test]
+fn illegal_opcode_locks_the_cpu() {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(LinearMemory::<{ 64 * 1024 }>::new(), recv);
+    cpu.mem.write(0xC000, 0x00); // NOP
+    cpu.mem.write(0xC001, 0xD3);
+    cpu.pc = RegisterPairValue::from(0xC000);
+    cpu.step();
+    cpu.step();
+    let fault = cpu.fault().expect("CPU should have locked up");
+    assert_eq!(fault.pc, 0xC001);
+    assert!(matches!(fault.error, Error::IllegalOpcode(0xD3)));
+    assert!(cpu.fault_status.lock().unwrap().is_some());
+    assert!(!cpu.paused());
+
+    // the CPU stays put and ignores interrupts, but time keeps passing
+    cpu.ime = true;
+    cpu.mem.write(0xFFFF, 0x01);
+    cpu.mem.request_interrupt(0x01);
+    assert_eq!(cpu.step(), 1);
+    assert_eq!(cpu.pc.as_u16(), 0xC002);
+
+    cpu.control_message(ControlMsg::Reset);
+    assert!(cpu.fault().is_none());
+    assert!(cpu.fault_status.lock().unwrap().is_none());
+}
+
+#[test]
+fn break_on_illegal_opcode_pauses() {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(LinearMemory::<{ 64 * 1024 }>::new(), recv);
+    cpu.mem.write(0xC000, 0xFD);
+    cpu.pc = RegisterPairValue::from(0xC000);
+    cpu.control_message(ControlMsg::BreakOnIllegal(true));
+    cpu.step();
+    assert!(cpu.paused());
+    cpu.control_message(ControlMsg::Continue);
+    assert!(!cpu.paused());
+    assert!(cpu.fault().is_some());
+}
+
+#[test]
+fn unhandled_control_message_is_ignored() {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(LinearMemory::<{ 64 * 1024 }>::new(), recv);
+    cpu.control_message(ControlMsg::ShowVRam(true));
+    assert!(cpu.fault().is_none());
+}
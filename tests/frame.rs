@@ -0,0 +1,29 @@
+use rustgb::frame::triple_buffer;
+use rustgb::ppu::Rgba;
+
+#[test]
+fn reader_gets_the_newest_frame_once() {
+    let (mut writer, mut reader) = triple_buffer();
+    assert!(reader.latest().is_none());
+
+    for shade in [10, 20, 30] {
+        writer.buffer().fill(Rgba::gray(shade));
+        writer.publish();
+    }
+    assert_eq!(reader.latest().unwrap()[0], Rgba::gray(30));
+    assert!(reader.latest().is_none());
+    assert_eq!(reader.current()[0], Rgba::gray(30));
+    assert_eq!(reader.published(), 3);
+}
+
+#[test]
+fn writer_never_draws_into_the_frame_being_read() {
+    let (mut writer, mut reader) = triple_buffer();
+    writer.buffer().fill(Rgba::WHITE);
+    writer.publish();
+    reader.latest();
+    writer.buffer().fill(Rgba::BLACK);
+    writer.publish();
+    writer.buffer().fill(Rgba::BLACK);
+    assert_eq!(reader.current()[0], Rgba::WHITE);
+}
@@ -0,0 +1,147 @@
+use common::mapped_memory;
+use rustgb::cpu::Cpu;
+use rustgb::joypad::JoypadKey;
+use rustgb::memory::{LinearMemory, MappedMemory, Memory, RegisterPairValue, RomOnlyMbc};
+use rustgb::{ControlMsg, Model, Register};
+use std::sync::mpsc;
+
+mod common;
+
+const IF: u16 = 0xFF0F;
+const IE: u16 = 0xFFFF;
+const DIV: u16 = 0xFF04;
+const JOYP: u16 = 0xFF00;
+const KEY1: u16 = 0xFF4D;
+
+fn cpu_with_program(pc: u16, program: &[u8]) -> Cpu<LinearMemory<{ 64 * 1024 }>> {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(LinearMemory::new(), recv);
+    for (i, byte) in program.iter().enumerate() {
+        cpu.mem.write(pc + i as u16, *byte);
+    }
+    cpu.pc = RegisterPairValue::from(pc);
+    cpu.sp = RegisterPairValue::from(0xD000);
+    cpu
+}
+
+fn mapped_cpu(program: &[u8]) -> Cpu<MappedMemory<RomOnlyMbc>> {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(mapped_memory(Model::Dmg), recv);
+    for (i, byte) in program.iter().enumerate() {
+        cpu.mem.write(0xC000 + i as u16, *byte);
+    }
+    cpu.pc = RegisterPairValue::from(0xC000);
+    cpu.sp = RegisterPairValue::from(0xD000);
+    cpu
+}
+
+#[test]
+fn halt_bug_reads_next_byte_twice() {
+    // HALT; LD A, n; ... is executed as LD A, 0x3E followed by the immediate as opcode
+    let mut cpu = cpu_with_program(0x1000, &[0x76, 0x3E, 0x04]);
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    cpu.cycle(); // HALT
+    assert_eq!(cpu.pc.as_u16(), 0x1001);
+    cpu.cycle(); // LD A, 0x3E
+    cpu.cycle();
+    assert_eq!(cpu.register(Register::A), 0x3E);
+    assert_eq!(cpu.pc.as_u16(), 0x1002);
+    let b = cpu.register(Register::B);
+    cpu.cycle(); // INC B
+    assert_eq!(cpu.register(Register::B), b.wrapping_add(1));
+}
+
+#[test]
+fn halt_without_ime_wakes_up_without_dispatch() {
+    let mut cpu = cpu_with_program(0x1000, &[0x76, 0x00]);
+    cpu.mem.write(IE, 0x04);
+    cpu.cycle(); // HALT
+    for _ in 0..10 {
+        cpu.cycle();
+    }
+    assert_eq!(cpu.pc.as_u16(), 0x1001);
+    cpu.mem.write(IF, 0x04);
+    cpu.cycle(); // wakes up
+    cpu.cycle(); // NOP
+    assert_eq!(cpu.pc.as_u16(), 0x1002);
+    assert_eq!(cpu.mem.get(IF), 0x04);
+}
+
+#[test]
+fn ei_before_halt_bug_returns_to_halt() {
+    let mut cpu = cpu_with_program(0x1000, &[0xFB, 0x76, 0x00]); // EI; HALT; NOP
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    cpu.cycle(); // EI
+    cpu.cycle(); // HALT, triggers the bug since IME is still 0
+    cpu.cycle(); // dispatch
+    assert_eq!(cpu.pc.as_u16(), 0x0040);
+    assert_eq!(cpu.mem.get(0xCFFF), 0x10);
+    assert_eq!(cpu.mem.get(0xCFFE), 0x01);
+}
+
+#[test]
+fn stop_waits_for_button_press_and_resets_div() {
+    let mut cpu = mapped_cpu(&[0x10, 0x00, 0x3C]); // STOP; INC A
+    cpu.mem.write(JOYP, 0x20); // select the d-pad
+    for _ in 0..300 {
+        cpu.mem.cycle();
+    }
+    assert_ne!(cpu.mem.get(DIV), 0);
+    cpu.cycle(); // STOP
+    assert_eq!(cpu.mem.get(DIV), 0);
+    let a = cpu.register(Register::A);
+    for _ in 0..10 {
+        cpu.cycle();
+    }
+    assert_eq!(cpu.register(Register::A), a);
+    assert_eq!(cpu.mem.get(DIV), 0);
+
+    cpu.mem.control_msg(ControlMsg::KeyDown(JoypadKey::Up)).unwrap();
+    cpu.cycle(); // leaves STOP mode
+    cpu.cycle(); // INC A
+    assert_eq!(cpu.register(Register::A), a.wrapping_add(1));
+}
+
+#[test]
+fn stop_switches_speed_on_cgb() {
+    let mut cpu = mapped_cpu(&[0x10, 0x00, 0x00]);
+    assert_eq!(cpu.mem.get(KEY1), 0xFF);
+    cpu.mem.model = Model::Cgb;
+    assert_eq!(cpu.mem.get(KEY1), 0x7E);
+    cpu.mem.write(KEY1, 0x01);
+    assert_eq!(cpu.mem.get(KEY1), 0x7F);
+    cpu.cycle(); // STOP
+    assert_eq!(cpu.mem.get(KEY1), 0xFE);
+    assert_eq!(cpu.pc.as_u16(), 0xC002);
+}
+
+#[test]
+fn save_states_keep_the_speed_switch_stall() {
+    let cgb_cpu = || {
+        let mut cpu = mapped_cpu(&[0x10, 0x00, 0x3C]); // STOP; INC A
+        cpu.mem.model = Model::Cgb;
+        cpu.mem.write(KEY1, 0x01);
+        cpu
+    };
+    // cycles until INC A has run
+    let cycles_to_inc = |cpu: &mut Cpu<MappedMemory<RomOnlyMbc>>| {
+        let a = cpu.register(Register::A);
+        let mut cycles = 0;
+        while cpu.register(Register::A) == a {
+            cpu.cycle();
+            cycles += 1;
+        }
+        cycles
+    };
+
+    let mut cpu = cgb_cpu();
+    cpu.cycle(); // STOP, the switch stalls the CPU for a while after it
+    let state = cpu.save_state();
+    let mut loaded = cgb_cpu();
+    loaded.load_state(&state).unwrap();
+    let expected = cycles_to_inc(&mut cpu);
+    assert!(expected > 2000);
+    assert_eq!(cycles_to_inc(&mut loaded), expected);
+}
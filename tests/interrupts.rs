@@ -0,0 +1,115 @@
+use rustgb::cpu::Cpu;
+use rustgb::memory::{LinearMemory, Memory, RegisterPairValue};
+use rustgb::Register;
+use std::sync::mpsc;
+
+const IF: u16 = 0xFF0F;
+const IE: u16 = 0xFFFF;
+
+fn cpu_with_program(pc: u16, program: &[u8]) -> Cpu<LinearMemory<{ 64 * 1024 }>> {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(LinearMemory::new(), recv);
+    for (i, byte) in program.iter().enumerate() {
+        cpu.mem.write(pc + i as u16, *byte);
+    }
+    cpu.pc = RegisterPairValue::from(pc);
+    cpu.sp = RegisterPairValue::from(0xD000);
+    cpu
+}
+
+#[test]
+fn highest_priority_interrupt_is_dispatched_first() {
+    let mut cpu = cpu_with_program(0x1000, &[0x00]);
+    cpu.ime = true;
+    cpu.mem.write(IE, 0x1F);
+    cpu.mem.write(IF, 0b10100); // Timer and Joypad
+    cpu.cycle();
+    assert_eq!(cpu.pc.as_u16(), 0x0050);
+    assert_eq!(cpu.mem.get(IF), 0b10000);
+    assert!(!cpu.ime);
+    assert_eq!(cpu.sp.as_u16(), 0xCFFE);
+    assert_eq!(cpu.mem.get(0xCFFF), 0x10);
+    assert_eq!(cpu.mem.get(0xCFFE), 0x00);
+}
+
+#[test]
+fn dispatch_takes_five_machine_cycles() {
+    let mut cpu = cpu_with_program(0x1000, &[0x00]);
+    cpu.mem.write(0x0040, 0x3C); // INC A
+    cpu.ime = true;
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    let a = cpu.register(Register::A);
+    for _ in 0..5 {
+        cpu.cycle();
+    }
+    assert_eq!(cpu.register(Register::A), a);
+    cpu.cycle();
+    assert_eq!(cpu.register(Register::A), a.wrapping_add(1));
+}
+
+#[test]
+fn ie_overwritten_by_pc_push_cancels_dispatch() {
+    // the high byte of PC (0x02) is pushed to 0xFFFF, disabling VBlank
+    let mut cpu = cpu_with_program(0x0200, &[0x00]);
+    cpu.sp = RegisterPairValue::from(0x0000);
+    cpu.ime = true;
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    cpu.cycle();
+    assert_eq!(cpu.pc.as_u16(), 0x0000);
+    assert_eq!(cpu.mem.get(IE), 0x02);
+    assert_eq!(cpu.mem.get(IF), 0x01);
+}
+
+#[test]
+fn ie_overwritten_by_pc_push_picks_new_interrupt() {
+    // the high byte of PC (0x04) is pushed to 0xFFFF, which only enables Timer
+    let mut cpu = cpu_with_program(0x0400, &[0x00]);
+    cpu.sp = RegisterPairValue::from(0x0000);
+    cpu.ime = true;
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x05);
+    cpu.cycle();
+    assert_eq!(cpu.pc.as_u16(), 0x0050);
+    assert_eq!(cpu.mem.get(IF), 0x01);
+}
+
+#[test]
+fn ei_takes_effect_after_next_instruction() {
+    let mut cpu = cpu_with_program(0x1000, &[0xFB, 0x00, 0x00]); // EI; NOP; NOP
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    cpu.cycle(); // EI
+    cpu.cycle(); // NOP
+    assert_eq!(cpu.pc.as_u16(), 0x1002);
+    cpu.cycle();
+    assert_eq!(cpu.pc.as_u16(), 0x0040);
+    assert_eq!(cpu.mem.get(0xCFFE), 0x02);
+}
+
+#[test]
+fn di_right_after_ei_prevents_dispatch() {
+    let mut cpu = cpu_with_program(0x1000, &[0xFB, 0xF3, 0x00]); // EI; DI; NOP
+    cpu.mem.write(IE, 0x01);
+    cpu.mem.write(IF, 0x01);
+    for _ in 0..3 {
+        cpu.cycle();
+    }
+    assert_eq!(cpu.pc.as_u16(), 0x1003);
+    assert!(!cpu.ime);
+}
+
+#[test]
+fn di_takes_effect_immediately() {
+    let mut cpu = cpu_with_program(0x1000, &[0xF3, 0x00, 0x00]); // DI; NOP; NOP
+    cpu.ime = true;
+    cpu.mem.write(IE, 0x01);
+    cpu.cycle(); // DI
+    assert!(!cpu.ime);
+    // requested before the next instruction, which a delayed DI would still let through
+    cpu.mem.write(IF, 0x01);
+    cpu.cycle();
+    cpu.cycle();
+    assert_eq!(cpu.pc.as_u16(), 0x1003);
+}
@@ -0,0 +1,117 @@
+use rustgb::cpu::Cpu;
+use rustgb::memory::{BusAccess, LinearMemory, Memory, RecordingMemory, RegisterPairValue};
+use rustgb::{RegisterPair, RegisterPairStk};
+use std::sync::mpsc;
+
+// runs one instruction at 0x1000 with all flags cleared, HL = 0xC000 and SP = 0xD000
+fn run(program: &[u8]) -> (usize, Vec<BusAccess>) {
+    let (_send, recv) = mpsc::channel();
+    let mut mem = LinearMemory::<{ 64 * 1024 }>::new();
+    for (i, byte) in program.iter().enumerate() {
+        mem.write(0x1000 + i as u16, *byte);
+    }
+    let mut cpu = Cpu::new(RecordingMemory::new(mem), recv);
+    cpu.pc = RegisterPairValue::from(0x1000);
+    cpu.sp = RegisterPairValue::from(0xD000);
+    *cpu.register_pair_mut(RegisterPair::HL) = RegisterPairValue::from(0xC000);
+    *cpu.register_pair_stk_mut(RegisterPairStk::AF).low_mut() = 0;
+    let cycles = cpu.step();
+    (cycles, cpu.mem.take_log())
+}
+
+fn read(cycle: usize, addr: u16, value: u8) -> BusAccess {
+    BusAccess::Read { cycle, addr, value }
+}
+
+fn write(cycle: usize, addr: u16, value: u8) -> BusAccess {
+    BusAccess::Write { cycle, addr, value }
+}
+
+// machine cycles of the unprefixed opcodes with all flags cleared, so NZ and NC branches are
+// taken and Z and C branches aren't. Zero marks opcodes that aren't tested here
+#[rustfmt::skip]
+const OPCODE_CYCLES: [usize; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    0, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    3, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    3, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 0, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    5, 3, 4, 4, 6, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+    5, 3, 4, 0, 6, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+];
+
+#[test]
+fn instruction_timing() {
+    for (opcode, expected) in OPCODE_CYCLES.iter().enumerate() {
+        if *expected == 0 {
+            continue;
+        }
+        let (cycles, _) = run(&[opcode as u8, 0x00, 0x00]);
+        assert_eq!(cycles, *expected, "opcode {:#04X}", opcode);
+    }
+}
+
+#[test]
+fn prefixed_instruction_timing() {
+    for opcode in 0..=0xFFu8 {
+        let expected = match (opcode & 0x07, opcode >> 6) {
+            (6, 1) => 3, // BIT b,[HL] doesn't write back
+            (6, _) => 4,
+            _ => 2,
+        };
+        let (cycles, _) = run(&[0xCB, opcode]);
+        assert_eq!(cycles, expected, "opcode 0xCB {:#04X}", opcode);
+    }
+}
+
+#[test]
+fn read_modify_write_accesses_hl_in_separate_cycles() {
+    let (_, log) = run(&[0x34]); // INC [HL]
+    assert_eq!(
+        log,
+        vec![
+            read(0, 0x1000, 0x34),
+            read(1, 0xC000, 0x00),
+            write(2, 0xC000, 0x01),
+        ]
+    );
+}
+
+#[test]
+fn store_to_immediate_address_happens_in_last_cycle() {
+    let (_, log) = run(&[0x08, 0x00, 0xC1]); // LD [0xC100], SP
+    assert_eq!(
+        log,
+        vec![
+            read(0, 0x1000, 0x08),
+            read(1, 0x1001, 0x00),
+            read(2, 0x1002, 0xC1),
+            write(3, 0xC100, 0x00),
+            write(4, 0xC101, 0xD0),
+        ]
+    );
+}
+
+#[test]
+fn call_pushes_after_internal_cycle() {
+    let (_, log) = run(&[0xCD, 0x00, 0x20]); // CALL 0x2000
+    assert_eq!(
+        log,
+        vec![
+            read(0, 0x1000, 0xCD),
+            read(1, 0x1001, 0x00),
+            read(2, 0x1002, 0x20),
+            write(4, 0xCFFF, 0x10),
+            write(5, 0xCFFE, 0x03),
+        ]
+    );
+}
@@ -0,0 +1,100 @@
+use common::mapped_memory;
+use rustgb::memory::{MappedMemory, Memory, RomOnlyMbc};
+use rustgb::Model;
+
+mod common;
+
+const DMA: u16 = 0xFF46;
+
+// the byte dma_memory fills WRAM with
+fn mem_byte(addr: u16) -> u8 {
+    let i = addr - 0xC000;
+    (i as u8).wrapping_mul(3) ^ (i >> 8) as u8
+}
+
+// memory with the LCD off, so OAM is accessible whenever no DMA is running
+fn dma_memory() -> MappedMemory<RomOnlyMbc> {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(0xFF40, 0x11);
+    for i in 0..0x2000 {
+        mem.write(0xC000 + i, mem_byte(0xC000 + i));
+    }
+    mem
+}
+
+fn run(mem: &mut MappedMemory<RomOnlyMbc>, cycles: usize) {
+    for _ in 0..cycles {
+        mem.cycle();
+    }
+}
+
+fn oam(mem: &MappedMemory<RomOnlyMbc>) -> Vec<u8> {
+    (0xFE00..0xFEA0).map(|addr| mem.get(addr)).collect()
+}
+
+fn source(mem: &MappedMemory<RomOnlyMbc>, start: u16) -> Vec<u8> {
+    (start..start + 0xA0).map(|addr| mem.get(addr)).collect()
+}
+
+#[test]
+fn transfer_takes_160_cycles_after_a_setup_cycle() {
+    let mut mem = dma_memory();
+    let expected = source(&mem, 0xC100);
+    mem.write(DMA, 0xC1);
+    mem.cycle(); // the cycle of the write
+    assert!(!mem.dma_active());
+    mem.cycle(); // setup
+    for _ in 0..160 {
+        assert!(mem.dma_active());
+        mem.cycle();
+    }
+    assert!(!mem.dma_active());
+    assert_eq!(oam(&mem), expected);
+    assert_eq!(mem.get(DMA), 0xC1);
+}
+
+#[test]
+fn cpu_sees_bus_conflicts_during_transfer() {
+    let mut mem = dma_memory();
+    mem.write(0xFF80, 0x42);
+    mem.write(DMA, 0xC1);
+    run(&mut mem, 2 + 10);
+    assert_eq!(mem.get(0xFE00), 0xFF);
+    assert_eq!(mem.get(0x0000), mem_byte(0xC10A));
+    assert_eq!(mem.get(0xD000), mem_byte(0xC10A));
+    assert_eq!(mem.get(0xFF80), 0x42);
+
+    // writes outside HRAM and IO don't reach the bus
+    mem.write(0xC000, 0x99);
+    run(&mut mem, 150);
+    assert_eq!(mem.get(0xC000), mem_byte(0xC000));
+}
+
+#[test]
+fn high_sources_mirror_wram() {
+    let mut mem = dma_memory();
+    let expected = source(&mem, 0xDE00);
+    mem.write(DMA, 0xFE);
+    run(&mut mem, 162);
+    assert_eq!(oam(&mem), expected);
+}
+
+#[test]
+fn restart_replaces_running_transfer() {
+    let mut mem = dma_memory();
+    let first = source(&mem, 0xC100);
+    let second = source(&mem, 0xC200);
+    mem.write(DMA, 0xC1);
+    run(&mut mem, 2 + 50);
+    mem.write(DMA, 0xC2);
+    // the old transfer keeps the bus until the new one has been set up
+    mem.cycle();
+    assert_eq!(mem.get(0x0000), mem_byte(0xC133));
+    mem.cycle();
+    assert_eq!(mem.get(0x0000), mem_byte(0xC200));
+    run(&mut mem, 160);
+    assert!(!mem.dma_active());
+    let oam = oam(&mem);
+    assert_eq!(oam, second);
+    assert_ne!(oam[..52], first[..52]);
+}
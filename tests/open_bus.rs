@@ -0,0 +1,69 @@
+use common::mapped_memory;
+use rustgb::memory::Memory;
+use rustgb::Model;
+
+mod common;
+
+#[test]
+fn every_address_can_be_read_and_written() {
+    for model in [Model::Dmg, Model::Cgb] {
+        let mut mem = mapped_memory(model);
+        for addr in 0..=0xFFFF {
+            let value = mem.get(addr);
+            if addr != 0xFF46 {
+                mem.write(addr, value);
+            }
+        }
+    }
+}
+
+#[test]
+fn unused_io_reads_open_bus() {
+    let mem = mapped_memory(Model::Dmg);
+    for addr in [0xFF03, 0xFF08, 0xFF0E, 0xFF27, 0xFF4C, 0xFF4D, 0xFF4F, 0xFF68, 0xFF70, 0xFF7F] {
+        assert_eq!(mem.get(addr), 0xFF, "{:#06X}", addr);
+    }
+}
+
+#[test]
+fn unused_register_bits_read_as_set() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(0xFF00, 0x00);
+    assert_eq!(mem.get(0xFF00), 0xCF);
+    mem.write(0xFF0F, 0x00);
+    assert_eq!(mem.get(0xFF0F), 0xE0);
+    mem.write(0xFF07, 0x00);
+    assert_eq!(mem.get(0xFF07), 0xF8);
+    assert_eq!(mem.get(0xFF02) & 0x7E, 0x7E);
+    assert_eq!(mem.get(0xFF41) & 0x80, 0x80);
+}
+
+#[test]
+fn prohibited_area_depends_on_model() {
+    let mut dmg = mapped_memory(Model::Dmg);
+    let mut cgb = mapped_memory(Model::Cgb);
+    dmg.write(0xFEB0, 0x12);
+    cgb.write(0xFEB0, 0x12);
+    assert_eq!(dmg.get(0xFEB0), 0x00);
+    assert_eq!(cgb.get(0xFEB0), 0xBB);
+    assert_eq!(cgb.get(0xFEF4), 0xFF);
+}
+
+#[test]
+fn echo_ram_starts_at_e000() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(0xE000, 0x42);
+    assert_eq!(mem.get(0xC000), 0x42);
+}
+
+#[test]
+fn cgb_switches_wram_banks() {
+    let mut mem = mapped_memory(Model::Cgb);
+    mem.write(0xD000, 0x11);
+    mem.write(0xFF70, 0x02);
+    assert_eq!(mem.get(0xFF70), 0xFA);
+    mem.write(0xD000, 0x22);
+    mem.write(0xFF70, 0x00); // bank 0 selects bank 1
+    assert_eq!(mem.get(0xFF70), 0xF9);
+    assert_eq!(mem.get(0xD000), 0x11);
+}
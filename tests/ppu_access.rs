@@ -0,0 +1,155 @@
+use common::mapped_memory;
+use rustgb::cpu::Cpu;
+use rustgb::memory::{MappedMemory, Memory, RegisterPairValue, RomOnlyMbc};
+use rustgb::Model;
+use std::sync::mpsc;
+
+mod common;
+
+const LCDC: u16 = 0xFF40;
+const STAT: u16 = 0xFF41;
+const LY: u16 = 0xFF44;
+
+// runs machine cycles until the PPU is in the given mode
+fn run_until_mode(mem: &mut MappedMemory<RomOnlyMbc>, mode: u8) {
+    for _ in 0..20_000 {
+        if mem.get(STAT) & 0x03 == mode {
+            return;
+        }
+        mem.cycle();
+    }
+    panic!("PPU never entered mode {}", mode);
+}
+
+// turns the LCD on and runs to the start of line 1, the first line after turning it on has no
+// OAM scan
+fn start_line_1(mem: &mut MappedMemory<RomOnlyMbc>) {
+    mem.write(LCDC, 0x91);
+    while mem.get(LY) != 1 {
+        mem.ppu.cycle();
+    }
+}
+
+#[test]
+fn vram_is_blocked_while_drawing() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LCDC, 0x11);
+    mem.write(0x8000, 0x42);
+    mem.write(LCDC, 0x91);
+    run_until_mode(&mut mem, 3);
+    assert_eq!(mem.get(0x8000), 0xFF);
+    mem.write(0x8000, 0x24);
+    assert_eq!(mem.get(0xFE00), 0xFF);
+    run_until_mode(&mut mem, 0);
+    assert_eq!(mem.get(0x8000), 0x42);
+}
+
+#[test]
+fn oam_is_blocked_while_scanning() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LCDC, 0x11);
+    mem.write(0xFE00, 0x42);
+    mem.write(LCDC, 0x91);
+    run_until_mode(&mut mem, 2);
+    assert_eq!(mem.get(0xFE00), 0xFF);
+    assert_eq!(mem.get(0xFEA0), 0xFF);
+    mem.write(0xFE00, 0x24);
+    assert_eq!(mem.get(0x8000), 0x00);
+    run_until_mode(&mut mem, 0);
+    assert_eq!(mem.get(0xFE00), 0x42);
+    assert_eq!(mem.get(0xFEA0), 0x00);
+}
+
+#[test]
+fn inc_dec_in_oam_corrupts_row_being_scanned() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LCDC, 0x11);
+    for i in 0..0xA0 {
+        mem.write(0xFE00 + i, i as u8);
+    }
+    start_line_1(&mut mem);
+    // the PPU reads one 8 byte row every machine cycle, row 5 is read in the sixth
+    for _ in 0..21 {
+        mem.ppu.cycle();
+    }
+    mem.inc_dec_bus(0xFE10);
+    mem.write(LCDC, 0x11);
+
+    let row = |index: u16| {
+        (0..8)
+            .map(|i| mem.get(0xFE00 + index * 8 + i))
+            .collect::<Vec<_>>()
+    };
+    let (a, b, c) = (0x2928u16, 0x2120u16, 0x2524u16);
+    let first = (((a ^ c) & (b ^ c)) ^ c).to_le_bytes();
+    assert_eq!(
+        row(5),
+        [first[0], first[1], 0x22, 0x23, 0x24, 0x25, 0x26, 0x27]
+    );
+    assert_eq!(row(4), (0x20..0x28).collect::<Vec<u8>>());
+    assert_eq!(row(6), (0x30..0x38).collect::<Vec<u8>>());
+}
+
+// OAM after LD HL, 0xFE10 and the given instruction run at the start of line 1
+fn oam_after(opcode: u8) -> Vec<u8> {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LCDC, 0x11);
+    for i in 0..0xA0 {
+        mem.write(0xFE00 + i, i as u8);
+    }
+    for (i, byte) in [0x21, 0x10, 0xFE, opcode].into_iter().enumerate() {
+        mem.write(0xC000 + i as u16, byte);
+    }
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(mem, recv);
+    cpu.pc = RegisterPairValue::from(0xC000);
+    start_line_1(&mut cpu.mem);
+    cpu.step();
+    cpu.step();
+    cpu.mem.write(LCDC, 0x11);
+    (0xFE00..0xFEA0).map(|addr| cpu.mem.get(addr)).collect()
+}
+
+// HL is incremented or decremented on the bus during the access, like INC HL does
+fn corrupts_oam_like_inc_hl(opcode: u8) {
+    let inc_hl = oam_after(0x23);
+    assert_ne!(inc_hl, oam_after(0x00));
+    assert_eq!(oam_after(opcode), inc_hl);
+}
+
+#[test]
+fn ld_hl_increment_from_a_corrupts_oam() {
+    corrupts_oam_like_inc_hl(0x22);
+}
+
+#[test]
+fn ld_hl_decrement_from_a_corrupts_oam() {
+    corrupts_oam_like_inc_hl(0x32);
+}
+
+#[test]
+fn ld_a_from_hl_increment_corrupts_oam() {
+    corrupts_oam_like_inc_hl(0x2A);
+}
+
+#[test]
+fn ld_a_from_hl_decrement_corrupts_oam() {
+    corrupts_oam_like_inc_hl(0x3A);
+}
+
+#[test]
+fn cgb_has_no_oam_corruption() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.model = Model::Cgb;
+    mem.write(LCDC, 0x11);
+    for i in 0..0xA0 {
+        mem.write(0xFE00 + i, i as u8);
+    }
+    start_line_1(&mut mem);
+    for _ in 0..21 {
+        mem.ppu.cycle();
+    }
+    mem.inc_dec_bus(0xFE10);
+    mem.write(LCDC, 0x11);
+    assert_eq!(mem.get(0xFE28), 0x28);
+}
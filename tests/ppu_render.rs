@@ -0,0 +1,492 @@
+use rustgb::apu::Apu;
+use rustgb::frame::{triple_buffer, FrameReader};
+use rustgb::memory::{MappedMemory, Mbc, Memory, RomOnlyMbc};
+use rustgb::ppu::{Ppu, Rgba};
+use rustgb::timer::Timer;
+use rustgb::ControlMsg;
+use std::sync::{Arc, Mutex};
+
+const LCDC: u16 = 0xFF40;
+const STAT: u16 = 0xFF41;
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LY: u16 = 0xFF44;
+const BGP: u16 = 0xFF47;
+const OBP0: u16 = 0xFF48;
+const OBP1: u16 = 0xFF49;
+const WY: u16 = 0xFF4A;
+const WX: u16 = 0xFF4B;
+
+// memory with the LCD off and the frame the PPU shows
+struct Screen {
+    mem: MappedMemory<RomOnlyMbc>,
+    frames: FrameReader,
+}
+
+impl Screen {
+    fn new() -> Self {
+        let (output, frames) = triple_buffer();
+        let ppu = Ppu::new(output, triple_buffer().0);
+        let apu = Apu::new(Arc::new(Mutex::new(Default::default())));
+        let mut mem = MappedMemory::new(RomOnlyMbc::new(vec![0; 0x8000]), ppu, Timer::new(), apu);
+        mem.write(LCDC, 0x11);
+        Self { mem, frames }
+    }
+
+    // tile 1 is solid colour 3, tile 0 stays blank
+    fn solid_tile(&mut self) {
+        for i in 0..16 {
+            self.mem.write(0x8010 + i, 0xFF);
+        }
+    }
+
+    // tile 2 only has its leftmost column set, in colour 3
+    fn left_edge_tile(&mut self) {
+        for i in 0..16 {
+            self.mem.write(0x8020 + i, 0x80);
+        }
+    }
+
+    fn object(&mut self, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        for (i, value) in [y, x, tile, flags].into_iter().enumerate() {
+            self.mem.write(0xFE00 + index * 4 + i as u16, value);
+        }
+    }
+
+    fn dot(&mut self) {
+        self.mem.ppu.cycle();
+    }
+
+    fn mode(&self) -> u8 {
+        self.mem.get(STAT) & 0x03
+    }
+
+    // turns the LCD on and measures mode 3 on line 1
+    fn mode_3_length(&mut self, lcdc: u8) -> usize {
+        self.mem.write(LCDC, lcdc);
+        while self.mem.get(LY) != 1 {
+            self.dot();
+        }
+        while self.mode() != 3 {
+            self.dot();
+        }
+        let mut dots = 0;
+        while self.mode() == 3 {
+            self.dot();
+            dots += 1;
+        }
+        dots
+    }
+
+    fn run_until_line(&mut self, line: u8) {
+        while self.mem.get(LY) != line {
+            self.dot();
+        }
+    }
+
+    // runs until the PPU hands over a frame
+    fn run_frame(&mut self) {
+        let published = self.frames.published();
+        while self.frames.published() == published {
+            self.dot();
+        }
+    }
+
+    // the newest frame the PPU handed over
+    fn frame(&mut self) -> &[Rgba] {
+        self.frames.latest();
+        self.frames.current()
+    }
+
+    fn pixel(&mut self, x: usize, y: usize) -> Rgba {
+        self.frame()[y * 160 + x]
+    }
+}
+
+#[test]
+fn mode_3_takes_172_dots_without_penalties() {
+    assert_eq!(Screen::new().mode_3_length(0x91), 172);
+}
+
+#[test]
+fn fine_scroll_delays_the_first_pixel() {
+    let mut screen = Screen::new();
+    screen.mem.write(SCX, 3);
+    assert_eq!(screen.mode_3_length(0x91), 175);
+}
+
+#[test]
+fn objects_stall_the_pixel_output() {
+    let mut screen = Screen::new();
+    // at the start of a background tile, fetching waits 5 dots for the tile
+    screen.mem.write(0xFE00, 16);
+    screen.mem.write(0xFE01, 8);
+    assert_eq!(screen.mode_3_length(0x93), 183);
+
+    // objects that are turned off cost nothing
+    let mut screen = Screen::new();
+    screen.mem.write(0xFE00, 16);
+    screen.mem.write(0xFE01, 8);
+    assert_eq!(screen.mode_3_length(0x91), 172);
+
+    // the second object on the same tile doesn't wait again
+    let mut screen = Screen::new();
+    for obj in [0xFE00, 0xFE04] {
+        screen.mem.write(obj, 16);
+        screen.mem.write(obj + 1, 12);
+    }
+    assert_eq!(screen.mode_3_length(0x93), 172 + 7 + 6);
+}
+
+#[test]
+fn window_restarts_the_fetcher() {
+    let mut screen = Screen::new();
+    screen.mem.write(WY, 0);
+    screen.mem.write(WX, 87);
+    assert_eq!(screen.mode_3_length(0xB1), 178);
+}
+
+#[test]
+fn background_and_objects_are_drawn() {
+    let mut screen = Screen::new();
+    screen.solid_tile();
+    // background tile at the second column of the second row
+    screen.mem.write(0x9821, 1);
+    // object using the same tile at (40, 0)
+    screen.mem.write(0xFE00, 16);
+    screen.mem.write(0xFE01, 48);
+    screen.mem.write(0xFE02, 1);
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(7, 8), Rgba::WHITE);
+    assert_eq!(screen.pixel(8, 8), Rgba::BLACK);
+    assert_eq!(screen.pixel(15, 15), Rgba::BLACK);
+    assert_eq!(screen.pixel(16, 8), Rgba::WHITE);
+    assert_eq!(screen.pixel(40, 0), Rgba::BLACK);
+    assert_eq!(screen.pixel(47, 7), Rgba::BLACK);
+    assert_eq!(screen.pixel(48, 0), Rgba::WHITE);
+}
+
+#[test]
+fn window_line_counter_pauses_while_the_window_is_off() {
+    let mut screen = Screen::new();
+    screen.solid_tile();
+    // the first window row is blank, the second solid
+    for i in 0..32 {
+        screen.mem.write(0x9C20 + i, 1);
+    }
+    screen.mem.write(WY, 0);
+    screen.mem.write(WX, 7);
+    screen.mem.write(LCDC, 0xF1);
+    screen.run_frame();
+    screen.run_until_line(4);
+    screen.mem.write(LCDC, 0xD1);
+    screen.run_until_line(12);
+    screen.mem.write(LCDC, 0xF1);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(0, 3), Rgba::WHITE);
+    assert_eq!(screen.pixel(0, 15), Rgba::WHITE);
+    assert_eq!(screen.pixel(0, 16), Rgba::BLACK);
+    assert_eq!(screen.pixel(159, 23), Rgba::BLACK);
+}
+
+#[test]
+fn window_can_be_enabled_after_wy_has_passed() {
+    let mut screen = Screen::new();
+    screen.solid_tile();
+    for i in 0..0x400 {
+        screen.mem.write(0x9C00 + i, 1);
+    }
+    screen.mem.write(WY, 10);
+    screen.mem.write(WX, 7);
+    screen.mem.write(LCDC, 0xD1);
+    screen.run_frame();
+    screen.run_until_line(20);
+    screen.mem.write(LCDC, 0xF1);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(0, 19), Rgba::WHITE);
+    assert_eq!(screen.pixel(0, 20), Rgba::BLACK);
+}
+
+#[test]
+fn window_edges() {
+    let window_at = |wx: u8| {
+        let mut screen = Screen::new();
+        screen.left_edge_tile();
+        for i in 0..32 {
+            screen.mem.write(0x9C00 + i, 2);
+        }
+        screen.mem.write(WY, 0);
+        screen.mem.write(WX, wx);
+        screen.mem.write(LCDC, 0xF1);
+        screen.run_frame();
+        (0..160)
+            .filter(|&x| screen.pixel(x, 0) == Rgba::BLACK)
+            .collect::<Vec<_>>()
+    };
+    // WX 0 hides the first 7 window pixels
+    assert_eq!(window_at(0)[..3], [1, 9, 17]);
+    assert_eq!(window_at(7)[..3], [0, 8, 16]);
+    assert_eq!(window_at(166), [159]);
+    assert_eq!(window_at(167), []);
+}
+
+// rows of the 8 tiles in the scrolling scene, none of them symmetric
+fn scene_tile_row(tile: u8, row: u8) -> (u8, u8) {
+    let low = (0x80u8 >> row) | tile;
+    let high = (tile << 4).rotate_left(row as u32) ^ 0x01;
+    (low, high)
+}
+
+fn scene_tile(x: usize, y: usize) -> u8 {
+    ((x * 3 + y * 5) % 8) as u8
+}
+
+// colour of a pixel of the 256x256 background, what the frame is checked against
+fn scene_pixel(x: usize, y: usize) -> Rgba {
+    let tile = scene_tile(x / 8 % 32, y / 8 % 32);
+    let (low, high) = scene_tile_row(tile, (y % 8) as u8);
+    let bit = 7 - x % 8;
+    let color = ((high >> bit) & 1) << 1 | (low >> bit) & 1;
+    Rgba::gray([255, 192, 96, 0][color as usize])
+}
+
+fn scene() -> Screen {
+    let mut screen = Screen::new();
+    screen.mem.write(BGP, 0b11_10_01_00);
+    for tile in 0..8 {
+        for row in 0..8 {
+            let (low, high) = scene_tile_row(tile, row);
+            let addr = 0x8000 + tile as u16 * 16 + row as u16 * 2;
+            screen.mem.write(addr, low);
+            screen.mem.write(addr + 1, high);
+        }
+    }
+    for y in 0..32 {
+        for x in 0..32 {
+            screen
+                .mem
+                .write(0x9800 + (y * 32 + x) as u16, scene_tile(x, y));
+        }
+    }
+    screen
+}
+
+#[test]
+fn scrolled_background_matches_the_map() {
+    for (scx, scy) in [(0, 0), (3, 5), (8, 16), (100, 37), (250, 200), (255, 255)] {
+        let mut screen = scene();
+        screen.mem.write(SCX, scx);
+        screen.mem.write(SCY, scy);
+        screen.mem.write(LCDC, 0x91);
+        screen.run_frame();
+
+        let expected = (0..144)
+            .flat_map(|y| (0..160).map(move |x| (x, y)))
+            .map(|(x, y)| scene_pixel(x + scx as usize, y + scy as usize))
+            .collect::<Vec<_>>();
+        assert!(
+            screen.frame() == expected,
+            "frame differs from the map at SCX {} SCY {}",
+            scx,
+            scy
+        );
+    }
+}
+
+#[test]
+fn scroll_changes_apply_from_the_next_line() {
+    let mut screen = scene();
+    screen.mem.write(LCDC, 0x91);
+    screen.run_frame();
+    screen.run_until_line(72);
+    screen.mem.write(SCX, 44);
+    screen.mem.write(SCY, 230);
+    screen.run_frame();
+
+    for (x, y) in [(0, 71), (159, 71), (0, 72), (17, 100), (159, 143)] {
+        let expected = if y < 72 {
+            scene_pixel(x, y)
+        } else {
+            scene_pixel(x + 44, y + 230)
+        };
+        assert_eq!(screen.pixel(x, y), expected, "pixel ({}, {})", x, y);
+    }
+}
+
+#[test]
+fn palettes_map_colour_ids() {
+    let mut screen = Screen::new();
+    screen.left_edge_tile();
+    // objects at x 0 with OBP0 and x 8 with OBP1, their colour 0 lets the background through
+    for (i, x, flags) in [(0, 8, 0x00), (1, 16, 0x10)] {
+        screen.mem.write(0xFE00 + i * 4, 16);
+        screen.mem.write(0xFE01 + i * 4, x);
+        screen.mem.write(0xFE02 + i * 4, 2);
+        screen.mem.write(0xFE03 + i * 4, flags);
+    }
+    screen.mem.write(BGP, 0b00_01_10_11);
+    screen.mem.write(OBP0, 0b01_00_00_00);
+    screen.mem.write(OBP1, 0b10_00_00_00);
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(0, 0), Rgba::gray(192));
+    assert_eq!(screen.pixel(1, 0), Rgba::BLACK);
+    assert_eq!(screen.pixel(8, 0), Rgba::gray(96));
+    assert_eq!(screen.pixel(9, 0), Rgba::BLACK);
+}
+
+// OBP0 shows colour 3 light, OBP1 dark
+fn object_screen() -> Screen {
+    let mut screen = Screen::new();
+    screen.solid_tile();
+    screen.mem.write(BGP, 0b11_10_01_00);
+    screen.mem.write(OBP0, 0b01_00_00_00);
+    screen.mem.write(OBP1, 0b10_00_00_00);
+    screen
+}
+
+#[test]
+fn only_ten_objects_per_line() {
+    let mut screen = object_screen();
+    for i in 0..11 {
+        screen.object(i, 16, 8 + i as u8 * 10, 1, 0);
+    }
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(90, 0), Rgba::gray(192));
+    assert_eq!(screen.pixel(100, 0), Rgba::WHITE);
+}
+
+#[test]
+fn lower_x_wins_and_oam_order_breaks_ties() {
+    let mut screen = object_screen();
+    screen.object(0, 16, 12, 1, 0x00);
+    screen.object(1, 16, 8, 1, 0x10);
+    screen.object(2, 32, 40, 1, 0x00);
+    screen.object(3, 32, 40, 1, 0x10);
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(7, 0), Rgba::gray(96));
+    assert_eq!(screen.pixel(8, 0), Rgba::gray(192));
+    assert_eq!(screen.pixel(32, 16), Rgba::gray(192));
+}
+
+#[test]
+fn tall_objects_ignore_the_low_tile_bit() {
+    let run = |flags: u8| {
+        let mut screen = object_screen();
+        screen.left_edge_tile();
+        for i in 0..16 {
+            screen.mem.write(0x8030 + i, 0xFF);
+        }
+        screen.object(0, 16, 8, 3, flags);
+        screen.mem.write(LCDC, 0x97);
+        screen.run_frame();
+        (screen.pixel(1, 0), screen.pixel(1, 15))
+    };
+    let (light, white) = (Rgba::gray(192), Rgba::WHITE);
+    assert_eq!(run(0x00), (white, light));
+    // flipping swaps the two halves
+    assert_eq!(run(0x40), (light, white));
+}
+
+#[test]
+fn object_height_can_change_during_mode_3() {
+    let mut screen = object_screen();
+    screen.left_edge_tile();
+    for i in 0..16 {
+        screen.mem.write(0x8030 + i, 0xFF);
+    }
+    screen.object(0, 16, 8, 3, 0x40);
+    screen.mem.write(LCDC, 0x97);
+    screen.run_frame();
+    // row 12 of the flipped tall object was picked by the OAM scan, tile 3 is drawn in its place
+    screen.run_until_line(12);
+    while screen.mode() != 3 {
+        screen.dot();
+    }
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+    assert_eq!(screen.pixel(1, 12), Rgba::gray(192));
+    assert_eq!(screen.pixel(1, 11), Rgba::WHITE);
+}
+
+#[test]
+fn background_hides_low_priority_objects() {
+    let mut screen = object_screen();
+    screen.mem.write(0x9800, 1);
+    screen.object(0, 16, 12, 1, 0x80);
+    screen.mem.write(LCDC, 0x93);
+    screen.run_frame();
+
+    assert_eq!(screen.pixel(4, 0), Rgba::BLACK);
+    assert_eq!(screen.pixel(8, 0), Rgba::gray(192));
+
+    // without the background it shows everywhere
+    screen.mem.write(LCDC, 0x92);
+    screen.run_frame();
+    assert_eq!(screen.pixel(4, 0), Rgba::gray(192));
+}
+
+#[test]
+fn lcd_off_stops_the_ppu() {
+    let mut screen = Screen::new();
+    screen.mem.write(LCDC, 0x91);
+    screen.run_until_line(50);
+    screen.mem.write(LCDC, 0x11);
+    assert_eq!(screen.mem.get(LY), 0);
+    assert_eq!(screen.mode(), 0);
+    for _ in 0..10_000 {
+        screen.dot();
+    }
+    assert_eq!(screen.mem.get(LY), 0);
+    assert_eq!(screen.mode(), 0);
+
+    // the first line after turning it back on starts without an OAM scan
+    screen.mem.write(LCDC, 0x91);
+    screen.dot();
+    assert_eq!(screen.mode(), 0);
+    screen.run_until_line(1);
+    screen.dot();
+    assert_eq!(screen.mode(), 2);
+}
+
+#[test]
+fn first_frame_after_lcd_on_is_not_shown() {
+    let mut screen = Screen::new();
+    screen.mem.write(LCDC, 0x91);
+    screen.run_until_line(144);
+    assert_eq!(screen.frames.published(), 0);
+    screen.run_until_line(0);
+    screen.run_until_line(144);
+    assert_eq!(screen.frames.published(), 1);
+}
+
+#[test]
+fn lcd_off_freezes_or_blanks_the_frame() {
+    let mut screen = Screen::new();
+    screen.solid_tile();
+    for i in 0..0x400 {
+        screen.mem.write(0x9800 + i, 1);
+    }
+    screen.mem.write(LCDC, 0x91);
+    screen.run_frame();
+    screen.mem.write(LCDC, 0x11);
+    assert_eq!(screen.pixel(80, 72), Rgba::BLACK);
+
+    screen
+        .mem
+        .control_msg(ControlMsg::BlankWhenLcdOff(true))
+        .unwrap();
+    screen.mem.write(LCDC, 0x91);
+    screen.run_frame();
+    screen.mem.write(LCDC, 0x11);
+    assert_eq!(screen.pixel(80, 72), Rgba::WHITE);
+}
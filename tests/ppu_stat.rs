@@ -0,0 +1,106 @@
+use common::mapped_memory;
+use rustgb::memory::{MappedMemory, Memory, RomOnlyMbc};
+use rustgb::{Model, CYCLES_PER_FRAME};
+
+mod common;
+
+const IF: u16 = 0xFF0F;
+const STAT: u16 = 0xFF41;
+const LY: u16 = 0xFF44;
+const LYC: u16 = 0xFF45;
+
+fn run_until_line(mem: &mut MappedMemory<RomOnlyMbc>, line: u8) {
+    while mem.get(LY) != line {
+        mem.cycle();
+    }
+}
+
+fn take_stat_interrupt(mem: &mut MappedMemory<RomOnlyMbc>) -> bool {
+    let requested = mem.get(IF) & 0x02 != 0;
+    mem.write(IF, 0x00);
+    requested
+}
+
+// LY and mode of the STAT interrupts over one frame, starting on line 1
+fn stat_interrupts_per_frame(mem: &mut MappedMemory<RomOnlyMbc>) -> Vec<(u8, u8)> {
+    run_until_line(mem, 1);
+    take_stat_interrupt(mem);
+    let mut lines = vec![];
+    for _ in 0..CYCLES_PER_FRAME {
+        mem.cycle();
+        if take_stat_interrupt(mem) {
+            lines.push((mem.get(LY), mem.get(STAT) & 0x03));
+        }
+    }
+    lines
+}
+
+#[test]
+fn sources_share_one_line() {
+    let mut mem = mapped_memory(Model::Dmg);
+    // mode 0 keeps the line high into mode 2, so only line 0 gets a mode 2 interrupt
+    mem.write(STAT, 0x28);
+    assert_eq!(stat_interrupts_per_frame(&mut mem).len(), 144 + 1);
+
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(STAT, 0x20);
+    assert_eq!(stat_interrupts_per_frame(&mut mem).len(), 144);
+}
+
+#[test]
+fn ly_reads_0_during_line_153() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LYC, 0);
+    mem.write(STAT, 0x40);
+    // LYC 0 fires once, while still in VBlank
+    assert_eq!(stat_interrupts_per_frame(&mut mem), [(0, 1)]);
+
+    // LY reads 153 for one machine cycle, LY=LYC sees 0 a cycle later
+    run_until_line(&mut mem, 153);
+    mem.cycle();
+    assert_eq!(mem.get(LY), 0);
+    mem.cycle();
+    assert_eq!(mem.get(STAT) & 0x07, 0x05);
+}
+
+#[test]
+fn ly_lyc_flag_is_clear_while_ly_changes() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LYC, 5);
+    while mem.ppu.read(LY) != 5 {
+        mem.ppu.cycle();
+    }
+    assert_eq!(mem.ppu.read(STAT) & 0x04, 0);
+    for _ in 0..4 {
+        mem.ppu.cycle();
+    }
+    assert_eq!(mem.ppu.read(STAT) & 0x04, 0x04);
+}
+
+#[test]
+fn stat_writes_cause_spurious_interrupts_on_dmg() {
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.write(LYC, 0xFF);
+    run_until_line(&mut mem, 144);
+    take_stat_interrupt(&mut mem);
+    mem.write(STAT, 0x00);
+    mem.cycle();
+    assert!(take_stat_interrupt(&mut mem));
+
+    // not if the line is already high
+    mem.write(STAT, 0x10);
+    mem.cycle();
+    take_stat_interrupt(&mut mem);
+    mem.write(STAT, 0x10);
+    mem.cycle();
+    assert!(!take_stat_interrupt(&mut mem));
+
+    let mut mem = mapped_memory(Model::Dmg);
+    mem.model = Model::Cgb;
+    mem.write(LYC, 0xFF);
+    run_until_line(&mut mem, 144);
+    take_stat_interrupt(&mut mem);
+    mem.write(STAT, 0x00);
+    mem.cycle();
+    assert!(!take_stat_interrupt(&mut mem));
+}
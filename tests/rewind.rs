@@ -0,0 +1,145 @@
+use common::{counting_rom, rom_memory};
+use rustgb::cpu::Cpu;
+use rustgb::rewind::{Rewind, RewindBuffer, RewindConfig};
+use rustgb::{ControlMsg, CYCLES_PER_FRAME};
+use std::sync::mpsc;
+use std::thread;
+
+mod common;
+
+#[test]
+fn steps_back_through_snapshots_and_stops_at_the_oldest() {
+    let mut buffer = RewindBuffer::new(RewindConfig::default());
+    let states = [
+        vec![1, 2, 3, 4],
+        vec![1, 2, 5],
+        vec![9; 6],
+        vec![9, 9, 9, 0, 9, 9],
+    ];
+    for state in &states {
+        buffer.push(state.clone());
+    }
+    assert_eq!(buffer.len(), 4);
+    for state in states.iter().rev() {
+        assert_eq!(buffer.step_back().as_ref(), Some(state));
+    }
+    assert_eq!(buffer.step_back().as_ref(), Some(&states[0]));
+    // only the oldest is left
+    assert_eq!(buffer.used(), states[0].len());
+}
+
+#[test]
+fn old_snapshots_are_dropped() {
+    // 1 second at one snapshot every 20 frames
+    let mut buffer = RewindBuffer::new(RewindConfig {
+        seconds: 1,
+        interval: 20,
+        memory: 1 << 20,
+    });
+    for i in 0..10 {
+        buffer.push(vec![i; 100]);
+    }
+    assert_eq!(buffer.len(), 3);
+
+    let mut buffer = RewindBuffer::new(RewindConfig {
+        seconds: 60,
+        interval: 1,
+        memory: 0,
+    });
+    for i in 0..10 {
+        buffer.push(vec![i; 100]);
+    }
+    assert_eq!(buffer.len(), 1);
+    assert_eq!(buffer.step_back(), Some(vec![9; 100]));
+}
+
+// waits for the worker to decode the snapshot
+fn step_back(rewind: &mut Rewind) -> Vec<u8> {
+    loop {
+        if let Some(state) = rewind.step_back() {
+            return state;
+        }
+        thread::yield_now();
+    }
+}
+
+#[test]
+fn snapshots_decoded_ahead_are_put_back() {
+    let mut rewind = Rewind::new(RewindConfig::default());
+    for i in 0..4 {
+        rewind.push(vec![i; 100]);
+    }
+    rewind.start();
+    assert_eq!(step_back(&mut rewind), vec![3; 100]);
+    assert_eq!(step_back(&mut rewind), vec![2; 100]);
+    // 1 is decoded by now, but rewinding stops at 2
+    rewind.stop();
+    rewind.push(vec![4; 100]);
+    rewind.start();
+    for i in [4, 1, 0, 0] {
+        assert_eq!(step_back(&mut rewind), vec![i; 100]);
+    }
+}
+
+#[test]
+fn tapping_the_rewind_key_quickly_never_blocks() {
+    let mut rewind = Rewind::new(RewindConfig::default());
+    for i in 0..50 {
+        rewind.push(vec![i; 100]);
+        for _ in 0..10 {
+            rewind.start();
+            rewind.stop();
+        }
+    }
+    // snapshots may have been dropped while the worker was behind, but none came back twice or
+    // out of order
+    rewind.start();
+    let mut previous = step_back(&mut rewind);
+    loop {
+        let state = step_back(&mut rewind);
+        if state == previous {
+            break;
+        }
+        assert!(state[0] < previous[0]);
+        previous = state;
+    }
+}
+
+#[test]
+fn rewinding_restores_the_snapshots_exactly() {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(rom_memory(counting_rom()), recv);
+    cpu.set_rewind(Some(RewindConfig {
+        seconds: 10,
+        interval: 1,
+        memory: 1 << 20,
+    }));
+    let mut snapshots = vec![];
+    for _ in 0..3 {
+        for _ in 0..CYCLES_PER_FRAME {
+            cpu.cycle();
+        }
+        snapshots.push(cpu.save_state());
+    }
+
+    // a snapshot is loaded at the end of every frame, unless the worker hasn't decoded it yet
+    cpu.control_message(ControlMsg::Rewind(true));
+    let mut frames = 0;
+    loop {
+        for _ in 0..CYCLES_PER_FRAME {
+            cpu.cycle();
+        }
+        if cpu.save_state() == snapshots[0] {
+            break;
+        }
+        frames += 1;
+        assert!(frames < 600, "never got back to the oldest snapshot");
+    }
+
+    // playing on from the oldest snapshot gets to the same state as the first time
+    cpu.control_message(ControlMsg::Rewind(false));
+    for _ in 0..CYCLES_PER_FRAME {
+        cpu.cycle();
+    }
+    assert!(cpu.save_state() == snapshots[1]);
+}
@@ -0,0 +1,154 @@
+use common::counting_rom;
+use rustgb::error::Error;
+use rustgb::savestate::{StateHeader, StateReader, StateWriter, THUMBNAIL_HEIGHT, THUMBNAIL_WIDTH};
+use rustgb::Emulator;
+
+mod common;
+
+// the state with one section changed and the checksum fixed up, so that only loading it fails
+fn edit_section(state: &[u8], tag: &[u8; 4], edit: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let thumbnail = u32::from_le_bytes(state[12..16].try_into().unwrap()) as usize;
+    let (header, mut sections) = state.split_at(16 + thumbnail + 4);
+    let mut data = vec![];
+    let mut edit = Some(edit);
+    while !sections.is_empty() {
+        let len = u32::from_le_bytes(sections[4..8].try_into().unwrap()) as usize;
+        let (section, rest) = sections.split_at(8 + len);
+        let mut contents = section[8..].to_vec();
+        if section[..4] == tag[..] {
+            (edit.take().unwrap())(&mut contents);
+        }
+        data.extend_from_slice(&section[..4]);
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(&contents);
+        sections = rest;
+    }
+    let mut out = header.to_vec();
+    let crc = out.len() - 4;
+    out[crc..].copy_from_slice(&crc32fast::hash(&data).to_le_bytes());
+    out.extend_from_slice(&data);
+    out
+}
+
+#[test]
+fn loading_a_state_replays_the_same_machine() {
+    let mut emulator = Emulator::new(counting_rom()).unwrap();
+    emulator.step_frame();
+    emulator.run_for_cycles(1234);
+    let state = emulator.save_state();
+    emulator.run_for_cycles(30_000);
+    let expected = emulator.save_state();
+
+    let mut emulator = Emulator::new(counting_rom()).unwrap();
+    emulator.load_state(&state).unwrap();
+    assert_eq!(emulator.save_state(), state);
+    emulator.run_for_cycles(30_000);
+    assert_eq!(emulator.save_state(), expected);
+}
+
+#[test]
+fn header_has_the_thumbnail() {
+    let emulator = Emulator::new(counting_rom()).unwrap();
+    let header = StateHeader::parse(&emulator.save_state()).unwrap();
+    assert_eq!(header.version, rustgb::savestate::VERSION);
+    assert_eq!(
+        header.thumbnail.len(),
+        THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4
+    );
+}
+
+#[test]
+fn bad_states_are_rejected_without_changes() {
+    let mut emulator = Emulator::new(counting_rom()).unwrap();
+    let state = emulator.save_state();
+    emulator.step_frame();
+    let before = emulator.save_state();
+
+    let mut corrupted = state.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 1;
+    assert!(matches!(
+        emulator.load_state(&corrupted),
+        Err(Error::InvalidSaveState(_))
+    ));
+    assert!(matches!(
+        emulator.load_state(&state[..100]),
+        Err(Error::InvalidSaveState(_))
+    ));
+
+    let mut other_rom = counting_rom();
+    other_rom[0x200] = 1;
+    let other = Emulator::new(other_rom).unwrap().save_state();
+    assert!(matches!(
+        emulator.load_state(&other),
+        Err(Error::SaveStateForOtherRom)
+    ));
+
+    // the oldest version that can read it
+    let mut newer = state.clone();
+    newer[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(matches!(
+        emulator.load_state(&newer),
+        Err(Error::SaveStateTooNew(_))
+    ));
+    assert!(emulator.save_state() == before);
+
+    // the serial port is loaded after memory, the PPU and the APU, those are put back
+    assert!(matches!(
+        emulator.load_state(&edit_section(&state, b"SERL", Vec::clear)),
+        Err(Error::InvalidSaveState(_))
+    ));
+    assert!(emulator.save_state() == before);
+}
+
+#[test]
+fn values_out_of_range_are_rejected() {
+    let mut emulator = Emulator::new(counting_rom()).unwrap();
+    emulator.step_frame();
+    let state = emulator.save_state();
+
+    // an object on the line that can't be on it, the PPU section ends with the object count
+    let off_the_line = edit_section(&state, b"PPU ", |data| {
+        assert_eq!(data.pop(), Some(0));
+        data.extend_from_slice(&[1, 200, 8, 0, 0, 0]);
+    });
+    assert!(matches!(
+        emulator.load_state(&off_the_line),
+        Err(Error::InvalidSaveState(_))
+    ));
+
+    // the noise channel's timer, followed by its length counter and envelope and the frame
+    // sequencer and sample counters
+    let timer_overflow = edit_section(&state, b"APU ", |data| {
+        let timer = data.len() - 21;
+        data[timer..timer + 4].copy_from_slice(&i32::MIN.to_le_bytes());
+    });
+    assert!(matches!(
+        emulator.load_state(&timer_overflow),
+        Err(Error::InvalidSaveState(_))
+    ));
+    assert!(emulator.save_state() == state);
+}
+
+#[test]
+fn unknown_sections_and_fields_are_skipped() {
+    let mut writer = StateWriter::new();
+    writer.section(b"NEW ");
+    writer.u32(1);
+    writer.section(b"TEST");
+    writer.u16(0x1234);
+    writer.bytes(&[1, 2, 3]);
+    writer.u64(5); // added in a later version
+    let data = writer.finish();
+
+    let mut reader = StateReader::new(&data).unwrap();
+    reader.section(b"TEST").unwrap();
+    assert_eq!(reader.u16().unwrap(), 0x1234);
+    let mut bytes = [0; 3];
+    reader.bytes_into(&mut bytes).unwrap();
+    assert_eq!(bytes, [1, 2, 3]);
+    assert!(matches!(
+        reader.section(b"MISS"),
+        Err(Error::InvalidSaveState(_))
+    ));
+}
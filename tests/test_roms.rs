@@ -0,0 +1,113 @@
+use common::rom_memory;
+use rustgb::cpu::Cpu;
+use rustgb::memory::{BusAccess, MappedMemory, Memory, RecordingMemory, RomOnlyMbc};
+use rustgb::{Register, CYCLES_PER_FRAME};
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+
+mod common;
+
+// test ROMs aren't part of the repo. These tests run the ones in test-roms/, laid out like the
+// mooneye-test-suite and gb-test-roms releases, with cargo test -- --ignored
+
+// emulated time a ROM gets to report its result
+const TIMEOUT: usize = 20 * 60 * CYCLES_PER_FRAME;
+
+fn rom(path: &str) -> Vec<u8> {
+    fs::read(Path::new("test-roms").join(path)).unwrap_or_else(|error| panic!("{path}: {error}"))
+}
+
+// Mooneye tests end with LD B, B, with the Fibonacci numbers 3, 5, 8, 13, 21 and 34 in B, C, D,
+// E, H and L if they passed
+fn mooneye(path: &str) -> Result<(), String> {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu: Cpu<MappedMemory<RomOnlyMbc>> = Cpu::new(rom_memory(rom(path)), recv);
+    let mut cycles = 0;
+    while cycles < TIMEOUT {
+        if cpu.mem.get(cpu.pc.as_u16()) == 0x40 {
+            let registers = [
+                Register::B,
+                Register::C,
+                Register::D,
+                Register::E,
+                Register::H,
+                Register::L,
+            ]
+            .map(|register| cpu.register(register));
+            return match registers {
+                [3, 5, 8, 13, 21, 34] => Ok(()),
+                _ => Err(format!("{path}: failed with {registers:02X?}")),
+            };
+        }
+        cycles += cpu.step();
+    }
+    Err(format!("{path}: timed out"))
+}
+
+// Blargg tests print their results through the serial port, a byte written to SB is sent when
+// SC is set to 0x81
+fn blargg(path: &str) -> Result<(), String> {
+    let (_send, recv) = mpsc::channel();
+    let mut cpu = Cpu::new(RecordingMemory::new(rom_memory(rom(path))), recv);
+    let mut output = String::new();
+    let mut cycles = 0;
+    let mut timeout = TIMEOUT;
+    while cycles < timeout {
+        cycles += cpu.step();
+        for access in cpu.mem.take_log() {
+            if let BusAccess::Write {
+                addr: 0xFF02,
+                value: 0x81,
+                ..
+            } = access
+            {
+                output.push(cpu.mem.inner.get(0xFF01) as char);
+            }
+        }
+        if output.contains("Passed") {
+            return Ok(());
+        }
+        // a second for the rest of the report, e.g. the failing instructions
+        if output.contains("Failed") && timeout == TIMEOUT {
+            timeout = cycles + 60 * CYCLES_PER_FRAME;
+        }
+    }
+    Err(format!("{path}:\n{output}"))
+}
+
+fn check_all(results: impl Iterator<Item = Result<(), String>>) {
+    let failures = results.filter_map(Result::err).collect::<Vec<_>>();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+#[ignore = "needs the Mooneye test ROMs in test-roms/mooneye"]
+fn mooneye_timer() {
+    let tests = [
+        "div_write",
+        "rapid_toggle",
+        "tim00",
+        "tim00_div_trigger",
+        "tim01",
+        "tim01_div_trigger",
+        "tim10",
+        "tim10_div_trigger",
+        "tim11",
+        "tim11_div_trigger",
+        "tima_reload",
+        "tima_write_reloading",
+        "tma_write_reloading",
+    ];
+    check_all(
+        tests
+            .iter()
+            .map(|test| mooneye(&format!("mooneye/acceptance/timer/{test}.gb"))),
+    );
+}
+
+#[test]
+#[ignore = "needs the Blargg test ROMs in test-roms/blargg"]
+fn blargg_instr_timing() {
+    blargg("blargg/instr_timing/instr_timing.gb").unwrap_or_else(|error| panic!("{error}"));
+}
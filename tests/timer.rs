@@ -0,0 +1,114 @@
+use rustgb::memory::Interrupt;
+use rustgb::timer::Timer;
+
+const DIV: u16 = 0xFF04;
+const TIMA: u16 = 0xFF05;
+const TMA: u16 = 0xFF06;
+const TAC: u16 = 0xFF07;
+
+// timer with a cleared system counter, incrementing TIMA every 4 machine cycles
+fn fast_timer() -> Timer {
+    let mut timer = Timer::new();
+    timer.write(DIV, 0);
+    timer.write(TAC, 0b101);
+    timer
+}
+
+fn run(timer: &mut Timer, cycles: usize) -> usize {
+    (0..cycles)
+        .filter(|_| matches!(timer.cycle(), Some(Interrupt::Timer)))
+        .count()
+}
+
+#[test]
+fn div_is_upper_byte_of_system_counter() {
+    let mut timer = Timer::new();
+    assert_eq!(timer.read(DIV), 0xAB);
+    timer.write(DIV, 0x12);
+    assert_eq!(timer.read(DIV), 0);
+    run(&mut timer, 63);
+    assert_eq!(timer.read(DIV), 0);
+    run(&mut timer, 1);
+    assert_eq!(timer.read(DIV), 1);
+}
+
+#[test]
+fn tima_increments_on_falling_edge() {
+    let mut timer = fast_timer();
+    run(&mut timer, 3);
+    assert_eq!(timer.read(TIMA), 0);
+    run(&mut timer, 1);
+    assert_eq!(timer.read(TIMA), 1);
+    run(&mut timer, 8);
+    assert_eq!(timer.read(TIMA), 3);
+}
+
+#[test]
+fn div_reset_with_selected_bit_set_increments_tima() {
+    let mut timer = fast_timer();
+    run(&mut timer, 2); // bit 3 of the counter is now set
+    timer.write(DIV, 0);
+    assert_eq!(timer.read(TIMA), 1);
+}
+
+#[test]
+fn tac_change_with_selected_bit_set_increments_tima() {
+    let mut timer = fast_timer();
+    run(&mut timer, 2);
+    timer.write(TAC, 0b001); // disabling the timer while the bit is set is a falling edge
+    assert_eq!(timer.read(TIMA), 1);
+    timer.write(TAC, 0b101);
+    timer.write(TAC, 0b110); // bit 5 is not set, so switching to it is a falling edge too
+    assert_eq!(timer.read(TIMA), 2);
+}
+
+#[test]
+fn overflow_reloads_tma_one_cycle_late() {
+    let mut timer = fast_timer();
+    timer.write(TIMA, 0xFF);
+    timer.write(TMA, 0x42);
+    assert_eq!(run(&mut timer, 4), 0);
+    assert_eq!(timer.read(TIMA), 0x00);
+    assert_eq!(run(&mut timer, 1), 1);
+    assert_eq!(timer.read(TIMA), 0x42);
+}
+
+#[test]
+fn tima_write_during_overflow_cancels_reload() {
+    let mut timer = fast_timer();
+    timer.write(TIMA, 0xFF);
+    timer.write(TMA, 0x42);
+    run(&mut timer, 4);
+    timer.write(TIMA, 0x10);
+    assert_eq!(run(&mut timer, 1), 0);
+    assert_eq!(timer.read(TIMA), 0x10);
+}
+
+#[test]
+fn tima_write_during_reload_is_ignored() {
+    let mut timer = fast_timer();
+    timer.write(TIMA, 0xFF);
+    timer.write(TMA, 0x42);
+    run(&mut timer, 5);
+    timer.write(TIMA, 0x10);
+    assert_eq!(timer.read(TIMA), 0x42);
+    run(&mut timer, 1);
+    assert_eq!(timer.read(TIMA), 0x42);
+}
+
+#[test]
+fn tma_write_during_reload_is_copied_to_tima() {
+    let mut timer = fast_timer();
+    timer.write(TIMA, 0xFF);
+    timer.write(TMA, 0x42);
+    run(&mut timer, 5);
+    timer.write(TMA, 0x99);
+    assert_eq!(timer.read(TIMA), 0x99);
+}
+
+#[test]
+fn tac_unused_bits_read_as_one() {
+    let mut timer = Timer::new();
+    timer.write(TAC, 0x05);
+    assert_eq!(timer.read(TAC), 0xFD);
+}